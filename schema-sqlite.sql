@@ -0,0 +1,83 @@
+-- The embedded database used with --sqlite. Applied on every start, so everything here
+-- must be safe to run again.
+
+CREATE TABLE IF NOT EXISTS roots (
+       root text PRIMARY KEY,
+       active boolean NOT NULL,
+       -- per-service overrides of `config`, as JSON
+       config text NOT NULL DEFAULT '{}',
+       last_scanned text
+);
+
+CREATE TABLE IF NOT EXISTS paths (
+       id integer PRIMARY KEY,
+       hash text NOT NULL,
+       path text NOT NULL,
+       codec text,
+       height integer,
+       width integer,
+       kbps real,
+       extension text,
+       bytes integer NOT NULL,
+       last_modified text NOT NULL,
+       in_progress boolean NOT NULL DEFAULT false,
+       priority integer NOT NULL DEFAULT 0,
+       encoded_path text,
+       encoded_from text,
+       settled_at text,
+       missing_since text,
+       health text,
+       health_errors text,
+       health_checked_at text
+);
+CREATE UNIQUE INDEX IF NOT EXISTS paths_path ON paths (path);
+
+CREATE TABLE IF NOT EXISTS reencode_jobs (
+       id integer PRIMARY KEY,
+       path_id integer REFERENCES paths (id) ON DELETE SET NULL,
+       source_path text NOT NULL,
+       output_path text,
+       state text NOT NULL DEFAULT 'running',
+       started_at text NOT NULL,
+       updated_at text NOT NULL,
+       finished_at text,
+       duration real,
+       percent real,
+       fps real,
+       speed real,
+       eta_seconds real,
+       profile text,
+       command text,
+       source_probe text,
+       output_probe text,
+       source_bytes integer,
+       output_bytes integer,
+       bytes_saved integer,
+       exit_status text,
+       stderr text,
+       error text,
+       quality_metric text,
+       quality_score real
+);
+CREATE INDEX IF NOT EXISTS reencode_jobs_state ON reencode_jobs (state);
+
+CREATE TABLE IF NOT EXISTS video_extensions (
+       extension text PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS config (
+       service text PRIMARY KEY,
+       config text NOT NULL
+);
+
+-- Defaults, only for a new database
+INSERT INTO video_extensions (extension)
+       SELECT column1 FROM (VALUES ('avi'),('mp4'),('m4v'),('mkv'),('iso'),('m2ts'))
+       WHERE NOT EXISTS (SELECT 1 FROM video_extensions);
+
+INSERT INTO config (service, config)
+       SELECT column1, column2 FROM (VALUES
+       ('scan', '{"interval": 3600}'),
+       ('clean', '{"interval": 3600}'),
+       ('reencode', '{"interval": 60, "order": "savings", "target_extension": "mkv", "target_codec": "hevc", "profiles": {"default": {"audio_codec": "aac"}}}'))
+       WHERE NOT EXISTS (SELECT 1 FROM config);
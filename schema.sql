@@ -12,7 +12,10 @@ SET ROLE media;
 
 CREATE TABLE roots (
        root text PRIMARY KEY,
-       active boolean NOT NULL
+       active boolean NOT NULL,
+       -- per-service overrides of `config`, e.g. '{"reencode": {"target_codec": "h264"}}'
+       config jsonb NOT NULL DEFAULT '{}'::jsonb,
+       last_scanned timestamp with time zone
 );
 
 CREATE TABLE paths (
@@ -26,10 +29,62 @@ CREATE TABLE paths (
        extension text,
        bytes bigint NOT NULL,
        last_modified timestamp with time zone NOT NULL,
-       in_progress boolean NOT NULL DEFAULT false
+       in_progress boolean NOT NULL DEFAULT false,
+       -- raised by `queue bump` to reencode a file before the others
+       priority integer NOT NULL DEFAULT 0,
+       -- set on a kept original once its encoded copy has been written elsewhere
+       encoded_path text,
+       -- set on an encoded file to the path it was encoded from
+       encoded_from text,
+       -- when a scan last found the file unmodified for the settle period; NULL while
+       -- it may still be being written
+       settled_at timestamp with time zone,
+       -- when clean first found the file gone; the row is removed once it has been
+       -- missing for clean's grace period, and the mark cleared if the file comes back
+       missing_since timestamp with time zone,
+       -- ok or damaged, from scan's optional health check; cleared when the file changes
+       health text,
+       -- what ffmpeg complained about while decoding the file
+       health_errors text,
+       health_checked_at timestamp with time zone
 );
 CREATE UNIQUE INDEX paths_path ON paths (path);
 
+CREATE TABLE reencode_jobs (
+       id bigserial PRIMARY KEY,
+       path_id bigint REFERENCES paths (id) ON DELETE SET NULL,
+       source_path text NOT NULL,
+       output_path text,
+       -- running, paused, succeeded, failed, skipped or cancelled
+       state text NOT NULL DEFAULT 'running',
+       started_at timestamp with time zone NOT NULL DEFAULT now(),
+       updated_at timestamp with time zone NOT NULL DEFAULT now(),
+       finished_at timestamp with time zone,
+       -- seconds of video in the source
+       duration double precision,
+       percent double precision,
+       fps double precision,
+       speed double precision,
+       eta_seconds double precision,
+       profile text,
+       command text,
+       source_probe jsonb,
+       output_probe jsonb,
+       source_bytes bigint,
+       output_bytes bigint,
+       bytes_saved bigint,
+       exit_status text,
+       -- the tail of ffmpeg's stderr
+       stderr text,
+       -- why the job did not succeed
+       error text,
+       -- vmaf or ssim, and the lowest score of the output's samples, when the profile
+       -- checks quality
+       quality_metric text,
+       quality_score double precision
+);
+CREATE INDEX reencode_jobs_state ON reencode_jobs (state);
+
 CREATE TABLE video_extensions (
        extension text PRIMARY KEY
 );
@@ -40,6 +95,21 @@ CREATE TABLE config (
        service text PRIMARY KEY,
        config jsonb NOT NULL
 );
+
+-- Running services reload their configuration when it changes. Row triggers, so that a
+-- statement that changes nothing doesn't notify; Postgres sends one notification per
+-- transaction however many rows change.
+CREATE FUNCTION notify_config_changed() RETURNS trigger AS $$
+BEGIN
+       PERFORM pg_notify('config_changed', TG_TABLE_NAME);
+       RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER config_changed AFTER INSERT OR UPDATE OR DELETE ON config
+       FOR EACH ROW EXECUTE FUNCTION notify_config_changed();
+CREATE TRIGGER roots_changed AFTER INSERT OR DELETE OR UPDATE OF root, active, config ON roots
+       FOR EACH ROW EXECUTE FUNCTION notify_config_changed();
+
 INSERT INTO config (service, config) VALUES
 ('scan',
 '{
@@ -52,6 +122,12 @@ INSERT INTO config (service, config) VALUES
 ('reencode',
 '{
   "interval": 60,
+  "order": "savings",
   "target_extension": "mkv",
-  "target_codec": "hevc"
+  "target_codec": "hevc",
+  "profiles": {
+    "default": {
+      "audio_codec": "aac"
+    }
+  }
 }'::jsonb);
@@ -0,0 +1,323 @@
+use crate::config::{Config, Sources};
+use crate::reencode;
+use crate::store::Store;
+use chrono::Local;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::path::Path;
+
+type VoidResult = Result<(), Box<dyn Error>>;
+
+pub fn queue_list(store: &mut dyn Store, config: &Config, limit: i64) -> VoidResult {
+    println!("Pending");
+    for candidate in reencode::pending(store, &config.reencode, limit)? {
+        println!(
+            "  {:>8}  {:>14}  {}",
+            candidate.id, candidate.bytes, candidate.path
+        );
+    }
+    // The latest job of each path that is still claimed: either running or stuck
+    println!("Claimed");
+    for job in store.claimed_jobs()? {
+        println!(
+            "  job {:>6}  {:<9}  {:>5.1}%  {}  {}",
+            job.id,
+            job.state,
+            job.percent.unwrap_or(0.0),
+            job.source_path,
+            job.error.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Move a file, or every file beneath a directory, ahead of the rest of the queue
+pub fn queue_bump(store: &mut dyn Store, path: &str, priority: i32) -> VoidResult {
+    let path = path.trim_end_matches('/');
+    let updated = store.set_priority(path, priority)?;
+    if updated == 0 {
+        return Err(format!("no known files at {}", path).into());
+    }
+    info!("Set priority {} on {} files", priority, updated);
+    Ok(())
+}
+
+/// Release the path of a finished job, or of one whose process died, so the reencode
+/// module will pick it up again
+pub fn queue_retry(store: &mut dyn Store, job_id: i64) -> VoidResult {
+    let stale_before = Local::now() - chrono::Duration::from_std(reencode::STALE_AFTER)?;
+    if !store.retry_job(job_id, stale_before)? {
+        return Err(format!("job {} is still encoding or has no path to retry", job_id).into());
+    }
+    info!("Job {} will be retried", job_id);
+    Ok(())
+}
+
+/// Files whose last health check found damage, with the start of what ffmpeg said
+pub fn damaged_list(store: &mut dyn Store, lines: usize) -> VoidResult {
+    for health in store.damaged()? {
+        println!(
+            "{}  checked {}",
+            health.path,
+            health.checked_at.format("%Y-%m-%d %H:%M")
+        );
+        for line in health.errors.unwrap_or_default().lines().take(lines) {
+            println!("    {}", line);
+        }
+    }
+    Ok(())
+}
+
+pub fn roots_add(store: &mut dyn Store, root: &str, active: bool) -> VoidResult {
+    store.add_root(root, active)
+}
+
+pub fn roots_remove(store: &mut dyn Store, root: &str) -> VoidResult {
+    if !store.remove_root(root)? {
+        return Err(format!("{} is not a root", root).into());
+    }
+    Ok(())
+}
+
+pub fn roots_list(store: &mut dyn Store) -> VoidResult {
+    for root in store.roots()? {
+        println!(
+            "{}  {}  scanned {}  {}",
+            root.root,
+            if root.active { "active" } else { "inactive" },
+            root.last_scanned
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or("never".to_string()),
+            root.config
+        );
+    }
+    Ok(())
+}
+
+/// The `config` of `service`, or with `root`, that root's overrides for it
+fn overrides(sources: &Sources, service: &str, root: Option<&str>) -> Result<Value, String> {
+    match root {
+        None => sources
+            .services
+            .get(service)
+            .cloned()
+            .ok_or_else(|| format!("no configuration for {}", service)),
+        Some(root) => sources
+            .roots
+            .iter()
+            .find(|r| r.root == root)
+            .map(|r| r.config.get(service).cloned().unwrap_or(json!({})))
+            .ok_or_else(|| format!("no configuration for {}", root)),
+    }
+}
+
+/// What the modules use of `overrides`: the database's settings with those of the
+/// config file on top, key by key
+fn in_effect(
+    sources: &Sources,
+    service: &str,
+    root: Option<&str>,
+) -> Result<Value, Box<dyn Error>> {
+    let mut config = overrides(sources, service, root)?;
+    if let (Some(Value::Object(file)), Some(config)) = (
+        sources.file_settings(service, root)?,
+        config.as_object_mut(),
+    ) {
+        for (key, value) in file {
+            config.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(config)
+}
+
+/// Print a service's configuration, or just one key of it, as the modules see it with
+/// `config_file`. With `root`, only that root's overrides are shown.
+pub fn config_get(
+    store: &mut dyn Store,
+    service: &str,
+    key: Option<&str>,
+    root: Option<&str>,
+    config_file: Option<&Path>,
+) -> VoidResult {
+    let sources = Sources::load(store, config_file)?;
+    let config = in_effect(&sources, service, root)?;
+    let value = match key {
+        None => &config,
+        Some(key) => config
+            .get(key)
+            .ok_or_else(|| format!("{} has no key {}", service, key))?,
+    };
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Set one key of a service's configuration, or of a root's overrides for it. `value`
+/// is parsed as JSON, falling back to a plain string. Nothing is saved unless the
+/// resulting configuration, along with `config_file`, is valid.
+pub fn config_set(
+    store: &mut dyn Store,
+    service: &str,
+    key: &str,
+    value: &str,
+    root: Option<&str>,
+    config_file: Option<&Path>,
+) -> VoidResult {
+    let value: Value =
+        serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    let mut sources = Sources::load(store, config_file)?;
+    let config = match root {
+        None => sources
+            .services
+            .entry(service.to_string())
+            .or_insert_with(|| json!({})),
+        Some(root) => {
+            let record = sources
+                .roots
+                .iter_mut()
+                .find(|r| r.root == root)
+                .ok_or_else(|| format!("{} is not a root", root))?;
+            record
+                .config
+                .as_object_mut()
+                .ok_or_else(|| format!("the configuration of {} is not an object", root))?
+                .entry(service)
+                .or_insert_with(|| json!({}))
+        }
+    };
+    config
+        .as_object_mut()
+        .ok_or_else(|| format!("the configuration of {} is not an object", service))?
+        .insert(key.to_string(), value);
+    Config::from_sources(&sources)?;
+    match root {
+        None => store.set_service_config(service, &sources.services[service]),
+        Some(root) => {
+            let record = sources
+                .roots
+                .iter()
+                .find(|r| r.root == root)
+                .expect("root went missing");
+            store.set_root_config(root, &record.config).map(|_| ())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{backend_tests, scratch_dir, MemoryStore, TestStore};
+    use crate::store::JobRecord;
+    use std::fs;
+
+    backend_tests!(
+        only_finished_jobs_are_retried,
+        jobs_whose_process_died_are_retried,
+    );
+
+    #[test]
+    fn config_set_saves_only_valid_changes() {
+        let mut store = MemoryStore::new();
+        store.add_root("/media/kids", true).unwrap();
+        assert!(config_set(&mut store, "reencode", "crf", "20", None, None).is_err());
+        assert!(config_set(&mut store, "reencode", "order", "biggest", None, None).is_err());
+        assert!(store.service_configs().unwrap().is_empty());
+
+        config_set(&mut store, "reencode", "order", "oldest", None, None).unwrap();
+        config_set(
+            &mut store,
+            "reencode",
+            "priority",
+            "5",
+            Some("/media/kids"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            store.service_configs().unwrap(),
+            vec![("reencode".to_string(), json!({"order": "oldest"}))]
+        );
+        assert_eq!(
+            store.roots().unwrap()[0].config,
+            json!({"reencode": {"priority": 5}})
+        );
+        assert!(config_set(&mut store, "reencode", "priority", "5", Some("/nope"), None).is_err());
+    }
+
+    #[test]
+    fn config_get_shows_the_file_over_the_database() {
+        let mut store = MemoryStore::new();
+        store.add_root("/media/kids", true).unwrap();
+        store
+            .set_service_config("reencode", &json!({"crf": 20, "preset": "slow"}))
+            .unwrap();
+        let file = scratch_dir("admin-config-get").join("config.toml");
+        fs::write(
+            &file,
+            "[reencode]\ncrf = 24\n[[roots]]\nroot = \"/media/kids\"\n\
+                [roots.reencode]\nprofile = \"phone\"\n",
+        )
+        .unwrap();
+        let sources = Sources::load(&mut store, Some(&file)).unwrap();
+        assert_eq!(
+            in_effect(&sources, "reencode", None).unwrap(),
+            json!({"crf": 24, "preset": "slow"})
+        );
+        assert_eq!(
+            in_effect(&sources, "reencode", Some("/media/kids")).unwrap(),
+            json!({"profile": "phone"})
+        );
+        let sources = Sources::load(&mut store, None).unwrap();
+        assert_eq!(
+            in_effect(&sources, "reencode", None).unwrap(),
+            json!({"crf": 20, "preset": "slow"})
+        );
+    }
+
+    fn only_finished_jobs_are_retried(store: &mut dyn TestStore) {
+        let path_id = store.add_file("/lib/a.avi", "h264", 1);
+        store.claim_path("/lib/a.avi").unwrap().unwrap();
+        let job = |state: &str| JobRecord {
+            path_id: Some(path_id),
+            source_path: "/lib/a.avi".to_string(),
+            state: state.to_string(),
+            updated_at: Local::now(),
+            ..JobRecord::default()
+        };
+        for state in ["running", "paused"] {
+            let id = store.insert_job(&job(state)).unwrap();
+            assert!(queue_retry(store, id).is_err());
+            store
+                .update_job(&JobRecord {
+                    id,
+                    ..job("failed")
+                })
+                .unwrap();
+        }
+        let id = store.insert_job(&job("failed")).unwrap();
+        queue_retry(store, id).unwrap();
+        assert!(!store.row("/lib/a.avi").unwrap().in_progress);
+    }
+
+    fn jobs_whose_process_died_are_retried(store: &mut dyn TestStore) {
+        let path_id = store.add_file("/lib/a.avi", "h264", 1);
+        store.claim_path("/lib/a.avi").unwrap().unwrap();
+        for state in ["running", "paused"] {
+            let stale = JobRecord {
+                path_id: Some(path_id),
+                source_path: "/lib/a.avi".to_string(),
+                state: state.to_string(),
+                updated_at: Local::now()
+                    - chrono::Duration::from_std(reencode::STALE_AFTER).unwrap()
+                    - chrono::Duration::minutes(1),
+                ..JobRecord::default()
+            };
+            let id = store.insert_job(&stale).unwrap();
+            queue_retry(store, id).unwrap();
+            assert!(!store.row("/lib/a.avi").unwrap().in_progress);
+            assert!(store.claimed_jobs().unwrap().is_empty());
+            let failed = store.jobs_in_state("failed").unwrap();
+            assert_eq!(failed.last().map(|j| j.id), Some(id));
+            store.claim_path("/lib/a.avi").unwrap().unwrap();
+        }
+    }
+}
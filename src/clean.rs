@@ -1,38 +1,592 @@
-use postgres::Client;
-use std::path::Path;
+mod orphans;
+
+use crate::config::{Config, ConfigError, Fields, Service};
+use crate::metrics;
+use crate::reencode;
+use crate::shutdown;
+use crate::store::{in_root, PathState, Store};
+use chrono::Local;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, DirEntry};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(3600);
+/// Paths read, checked and deleted at a time
+const DEFAULT_BATCH_SIZE: u64 = 1000;
+/// Most paths removed in one run, so a mistake can't empty the table
+const DEFAULT_MAX_DELETES: u64 = 10000;
+/// How long a file stays missing before its row is removed, in case it comes back
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Share of a root's paths that may be missing before clean suspects the root itself
+const DEFAULT_MAX_DELETE_PERCENT: f64 = 20.0;
+/// Removing this many paths from a root is never suspicious, whatever the share
+const MIN_GUARDED_DELETES: usize = 10;
+
+/// Checks that a root is really there before its missing files are forgotten
+#[derive(Debug)]
+struct Guard {
+    /// A file, relative to the root, that only exists while it is mounted
+    sentinel: Option<String>,
+    max_delete_percent: f64,
+}
+
+impl Guard {
+    const KEYS: [&'static str; 2] = ["sentinel", "max_delete_percent"];
+
+    fn from_fields(fields: &Fields) -> Result<Guard, ConfigError> {
+        Ok(Guard {
+            sentinel: fields.string("sentinel")?,
+            max_delete_percent: fields
+                .parse("max_delete_percent", |v| {
+                    v.as_f64()
+                        .filter(|p| (0.0..=100.0).contains(p))
+                        .ok_or_else(|| "must be a number from 0 to 100".to_string())
+                })?
+                .unwrap_or(DEFAULT_MAX_DELETE_PERCENT),
+        })
+    }
+
+    /// Why the directory `root` looks unmounted, if it does
+    fn unmounted(&self, root: &Path) -> Option<String> {
+        match fs::read_dir(root) {
+            Err(e) => return Some(format!("can't list it: {}", e)),
+            Ok(mut entries) => {
+                if entries.next().is_none() {
+                    return Some("it is empty".to_string());
+                }
+            }
+        }
+        match &self.sentinel {
+            Some(sentinel) if !root.join(sentinel).exists() => {
+                Some(format!("its sentinel {} is missing", sentinel))
+            }
+            _ => None,
+        }
+    }
+
+    /// Why removing `missing` of `total` paths looks like a mistake, if it does
+    fn too_many(&self, missing: usize, total: usize) -> Option<String> {
+        let percent = 100.0 * missing as f64 / total.max(1) as f64;
+        if missing > MIN_GUARDED_DELETES && percent > self.max_delete_percent {
+            Some(format!(
+                "{} of its {} paths ({:.0}%) are missing, more than max_delete_percent {}",
+                missing, total, percent, self.max_delete_percent
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Settings of the clean module
+#[derive(Debug)]
+pub struct CleanConfig {
+    pub interval: Duration,
+    batch_size: i64,
+    max_deletes: u64,
+    grace_period: Duration,
+    /// Delete the files encodes left behind rather than only reporting them
+    remove_orphans: bool,
+    /// Where encodes keep their scratch files
+    temp_dir: PathBuf,
+    /// For paths outside of every root
+    guard: Guard,
+    roots: Vec<(String, Guard)>,
+}
+
+impl CleanConfig {
+    pub fn from_service(service: &Service) -> Result<CleanConfig, ConfigError> {
+        service.only(
+            &[
+                "interval",
+                "batch_size",
+                "max_deletes",
+                "grace_period",
+                "remove_orphans",
+            ],
+            &Guard::KEYS,
+        )?;
+        let global = &service.global;
+        let batch_size = global.count("batch_size")?.unwrap_or(DEFAULT_BATCH_SIZE);
+        let roots = service
+            .roots()
+            .into_iter()
+            .map(|(root, fields)| Ok((root.to_string(), Guard::from_fields(&fields)?)))
+            .collect::<Result<_, ConfigError>>()?;
+        Ok(CleanConfig {
+            interval: global.seconds("interval")?.unwrap_or(DEFAULT_INTERVAL),
+            batch_size: i64::try_from(batch_size)
+                .ok()
+                .filter(|b| *b > 0)
+                .ok_or_else(|| global.error("batch_size", "must be at least 1"))?,
+            max_deletes: global.count("max_deletes")?.unwrap_or(DEFAULT_MAX_DELETES),
+            grace_period: global
+                .seconds("grace_period")?
+                .unwrap_or(DEFAULT_GRACE_PERIOD),
+            remove_orphans: global.bool("remove_orphans")?.unwrap_or(false),
+            temp_dir: PathBuf::from(reencode::TEMP_DIR),
+            guard: Guard::from_fields(global)?,
+            roots,
+        })
+    }
+}
+
+/// The paths of one root, or of none, as clean finds them
+struct Group<'a> {
+    root: Option<&'a str>,
+    guard: &'a Guard,
+    /// Why the group's paths are left alone this run, once that's known
+    blocked: Option<String>,
+    total: usize,
+    missing: Vec<PathState>,
+    /// Marked missing, but there again
+    found: Vec<String>,
+}
+
+impl Group<'_> {
+    fn name(&self) -> String {
+        match self.root {
+            Some(root) => format!("root {}", root),
+            None => "paths outside of every root".to_string(),
+        }
+    }
+}
+
+/// The files in each directory, listed once per run instead of checking every path
+#[derive(Default)]
+struct Listings {
+    /// `None` for a directory that exists but can't be listed
+    files: HashMap<PathBuf, Option<HashSet<OsString>>>,
+}
+
+impl Listings {
+    fn list(dir: &Path) -> Option<HashSet<OsString>> {
+        match fs::read_dir(dir) {
+            Ok(entries) => Some(
+                entries
+                    .flatten()
+                    .filter(is_file)
+                    .map(|e| e.file_name())
+                    .collect(),
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => Some(HashSet::new()),
+            Err(e) => {
+                warn!("Can't list {:?}; keeping its paths: {}", dir, e);
+                None
+            }
+        }
+    }
+
+    /// Partial targets of encodes in the directories listed so far
+    fn partials(&self) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .filter_map(|(dir, files)| Some((dir, files.as_ref()?)))
+            .flat_map(|(dir, files)| {
+                files
+                    .iter()
+                    .filter(|name| name.to_str().is_some_and(reencode::is_partial))
+                    .map(move |name| dir.join(name))
+            })
+            .collect()
+    }
+
+    /// Whether `path` is no longer a file
+    fn missing(&mut self, path: &str) -> bool {
+        let path = Path::new(path);
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return false,
+        };
+        self.files
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| Listings::list(dir))
+            .as_ref()
+            .is_some_and(|files| !files.contains(name))
+    }
+}
+
+/// A file, or a symlink to one
+fn is_file(entry: &DirEntry) -> bool {
+    entry
+        .file_type()
+        .is_ok_and(|t| t.is_file() || (t.is_symlink() && entry.path().is_file()))
+}
+
+/// What one run of clean did
+#[derive(Debug, Default, PartialEq)]
+struct Cleaned {
+    /// Paths newly found missing
+    marked: u64,
+    /// Missing paths whose files came back
+    restored: u64,
+    /// Paths missing for longer than the grace period, removed
+    purged: u64,
+    /// Files left behind by encodes, reported or removed
+    orphans: u64,
+}
+
+/// Mark the paths of files that no longer exist as missing and remove those missing for
+/// longer than `grace_period`, up to `max_deletes`, unless so many of a root's are missing
+/// that the root itself seems to be gone. Paths whose files came back are restored. Then
+/// deal with the files encodes left behind.
+fn clean(store: &mut dyn Store, config: &CleanConfig) -> Result<Cleaned, Box<dyn Error>> {
+    let mut groups: Vec<Group> = config
+        .roots
+        .iter()
+        .map(|(root, guard)| Group {
+            root: Some(root),
+            guard,
+            blocked: guard.unmounted(Path::new(root)),
+            total: 0,
+            missing: Vec::new(),
+            found: Vec::new(),
+        })
+        .collect();
+    groups.push(Group {
+        root: None,
+        guard: &config.guard,
+        blocked: None,
+        total: 0,
+        missing: Vec::new(),
+        found: Vec::new(),
+    });
+    let mut listings = Listings::default();
+    let mut after: Option<String> = None;
+    loop {
+        if shutdown::requested() {
+            return Ok(Cleaned::default());
+        }
+        let batch = store.path_states(after.as_deref(), config.batch_size)?;
+        after = match batch.last() {
+            Some(last) => Some(last.path.clone()),
+            None => break,
+        };
+        for state in batch {
+            let group = groups
+                .iter_mut()
+                .filter(|g| g.root.is_none_or(|root| in_root(&state.path, root)))
+                .max_by_key(|g| g.root.map_or(0, str::len))
+                .expect("no group for paths outside of every root");
+            group.total += 1;
+            // Don't bother listing the directories of a root that looks unmounted
+            if group.blocked.is_some() {
+                continue;
+            }
+            if listings.missing(&state.path) {
+                group.missing.push(state);
+            } else if state.missing_since.is_some() {
+                group.found.push(state.path);
+            }
+        }
+    }
+
+    let mut cleaned = Cleaned::default();
+    let found: Vec<String> = groups.iter_mut().flat_map(|g| g.found.drain(..)).collect();
+    for batch in found.chunks(config.batch_size as usize) {
+        for path in batch.iter() {
+            info!("{} exists again; no longer missing", path);
+        }
+        cleaned.restored += store.mark_found(batch)?;
+    }
+
+    let mut blocked = 0;
+    let mut missing = Vec::new();
+    for mut group in groups {
+        if group.blocked.is_none() {
+            group.blocked = group.guard.too_many(group.missing.len(), group.total);
+        }
+        match &group.blocked {
+            Some(reason) => {
+                blocked += 1;
+                error!(
+                    "Not removing the missing files of {}: {}",
+                    group.name(),
+                    reason
+                );
+            }
+            None => missing.extend(group.missing),
+        }
+    }
+    metrics::CLEAN_BLOCKED_ROOTS.set(blocked);
+
+    // A path found missing now expires now too when there's no grace period
+    let now = Local::now();
+    let expired_before = now - chrono::Duration::from_std(config.grace_period)?;
+    let mut newly_missing = Vec::new();
+    let mut doomed = Vec::new();
+    let mut capped = false;
+    for state in missing {
+        let expired = state.missing_since.unwrap_or(now) <= expired_before;
+        if expired && (doomed.len() as u64) < config.max_deletes {
+            doomed.push(state.path);
+        } else {
+            capped |= expired;
+            if state.missing_since.is_none() {
+                newly_missing.push(state.path);
+            }
+        }
+    }
+
+    for batch in newly_missing.chunks(config.batch_size as usize) {
+        for path in batch.iter() {
+            info!("{} does not exist; marking it missing", path);
+        }
+        cleaned.marked += store.mark_missing(batch)?;
+    }
+    for batch in doomed.chunks(config.batch_size as usize) {
+        for path in batch.iter() {
+            info!(
+                "{} has been missing too long; removing it from the database",
+                path
+            );
+        }
+        cleaned.purged += store.delete_paths(batch)?;
+    }
+    if capped {
+        warn!(
+            "Removed {} paths, the most clean.max_deletes allows in one run; \
+                the rest wait for the next run",
+            cleaned.purged
+        );
+    }
+    cleaned.orphans = orphans::sweep(store, config, listings.partials())?;
+    Ok(cleaned)
+}
 
 pub struct Clean {}
 impl crate::module::Module for Clean {
     fn module_name(&self) -> &str {
         "clean"
     }
-    fn module_iteration(&self, connection: &mut Client) {
+    fn interval(&self, config: &Config) -> Duration {
+        config.clean.interval
+    }
+    fn module_iteration(&self, store: &mut dyn Store, config: &Config) {
         info!("Checking all paths for non-existant files");
-        let mut done = false;
-        let mut offset: i32 = 0;
-        let limit: i32 = 100;
-        while !done {
-            done = true;
-            debug!("Selecting paths");
-            let rows = connection
-                .query(
-                    "SELECT path FROM paths ORDER BY path DESC OFFSET $1::int4 LIMIT $2::int4",
-                    &[&offset, &limit],
-                )
-                .unwrap();
-            debug!("Got {:?}", rows);
-            for row in rows.iter() {
-                done = false;
-                let path: String = row.get(0);
-                debug!("Checking {}", &path);
-                if !Path::new(&path).is_file() {
-                    info!("{} does not exist; removing it from the database", &path);
-                    connection
-                        .execute("DELETE FROM paths WHERE path = $1", &[&path])
-                        .unwrap();
-                }
+        match clean(store, &config.clean) {
+            Ok(cleaned) => info!(
+                "Marked {} paths missing, restored {} and removed {} missing for longer than \
+                    the grace period; found {} files left behind by encodes",
+                cleaned.marked, cleaned.restored, cleaned.purged, cleaned.orphans
+            ),
+            Err(e) => warn!("Stopped cleaning: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::Module;
+    use crate::store::memory::{backend_tests, scratch_dir, TestStore};
+    use crate::store::JobRecord;
+    use serde_json::json;
+    use std::fs;
+
+    backend_tests!(
+        forgets_files_that_are_gone,
+        stops_at_max_deletes,
+        leaves_roots_that_look_unmounted_alone,
+        refuses_to_remove_most_of_a_root,
+        missing_files_wait_out_the_grace_period_unless_they_return,
+        finds_what_encodes_left_behind,
+    );
+
+    /// Adds one existing file and `gone` missing ones, all in a scratch directory named
+    /// after the test, returning the existing one
+    fn add_missing(store: &mut dyn TestStore, name: &str, gone: usize) -> String {
+        let dir = scratch_dir(&format!("{}-{}", name, store.kind()));
+        let kept = dir.join("kept.mp4");
+        fs::write(&kept, b"video").unwrap();
+        let kept = kept.to_str().unwrap().to_string();
+        store.add_file(&kept, "h264", 5);
+        for i in 0..gone {
+            store.add_file(&format!("{}/gone{}.mp4", dir.display(), i), "h264", 5);
+        }
+        kept
+    }
+
+    fn paths(store: &mut dyn TestStore) -> Vec<String> {
+        store
+            .library()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.path)
+            .collect()
+    }
+
+    fn forgets_files_that_are_gone(store: &mut dyn TestStore) {
+        let kept = add_missing(store, "clean-gone", 250);
+        store
+            .set_service_config(
+                "clean",
+                &json!({"batch_size": 100, "max_delete_percent": 100, "grace_period": 0}),
+            )
+            .unwrap();
+        let config = Config::load(store, None).unwrap();
+        Clean {}.module_iteration(store, &config);
+        assert_eq!(paths(store), vec![kept]);
+    }
+
+    fn stops_at_max_deletes(store: &mut dyn TestStore) {
+        add_missing(store, "clean-capped", 30);
+        store
+            .set_service_config(
+                "clean",
+                &json!({
+                    "batch_size": 7,
+                    "max_deletes": 10,
+                    "max_delete_percent": 100,
+                    "grace_period": 0
+                }),
+            )
+            .unwrap();
+        let config = Config::load(store, None).unwrap();
+        assert_eq!(clean(store, &config.clean).unwrap().purged, 10);
+        assert_eq!(paths(store).len(), 21);
+    }
+
+    fn leaves_roots_that_look_unmounted_alone(store: &mut dyn TestStore) {
+        let kept = add_missing(store, "clean-unmounted", 3);
+        let root = Path::new(&kept)
+            .parent()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        store.add_root(&root, true).unwrap();
+        store
+            .set_service_config("clean", &json!({"grace_period": 0}))
+            .unwrap();
+        store
+            .set_root_config(&root, &json!({"clean": {"sentinel": ".mounted"}}))
+            .unwrap();
+        let config = Config::load(store, None).unwrap();
+        assert_eq!(clean(store, &config.clean).unwrap().purged, 0);
+
+        fs::write(Path::new(&root).join(".mounted"), b"").unwrap();
+        assert_eq!(clean(store, &config.clean).unwrap().purged, 3);
+
+        store.add_file(&format!("{}/gone.mp4", root), "h264", 5);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(clean(store, &config.clean).unwrap().purged, 0);
+        assert_eq!(paths(store).len(), 2);
+    }
+
+    fn refuses_to_remove_most_of_a_root(store: &mut dyn TestStore) {
+        let kept = add_missing(store, "clean-most", 30);
+        let root = Path::new(&kept)
+            .parent()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        store.add_root(&root, true).unwrap();
+        store
+            .set_service_config("clean", &json!({"grace_period": 0}))
+            .unwrap();
+        let config = Config::load(store, None).unwrap();
+        assert_eq!(clean(store, &config.clean).unwrap().purged, 0);
+        assert_eq!(paths(store).len(), 31);
+
+        store
+            .set_root_config(&root, &json!({"clean": {"max_delete_percent": 100}}))
+            .unwrap();
+        let config = Config::load(store, None).unwrap();
+        assert_eq!(clean(store, &config.clean).unwrap().purged, 30);
+    }
+
+    fn missing_files_wait_out_the_grace_period_unless_they_return(store: &mut dyn TestStore) {
+        let kept = add_missing(store, "clean-grace", 2);
+        let mut config = Config::load(store, None).unwrap();
+        config.clean.temp_dir = scratch_dir(&format!("clean-grace-temp-{}", store.kind()));
+        let cleaned = clean(store, &config.clean).unwrap();
+        assert_eq!(cleaned.marked, 2);
+        assert_eq!(cleaned.purged, 0);
+        assert_eq!(paths(store).len(), 3);
+        assert!(store.row(&kept).unwrap().missing_since.is_none());
+
+        let back = kept.replace("kept", "gone0");
+        fs::write(&back, b"video").unwrap();
+        // As if the grace period had passed
+        config.clean.grace_period = Duration::ZERO;
+        let cleaned = clean(store, &config.clean).unwrap();
+        assert_eq!(
+            cleaned,
+            Cleaned {
+                marked: 0,
+                restored: 1,
+                purged: 1,
+                orphans: 0
             }
-            offset += limit;
+        );
+        assert_eq!(paths(store), vec![back, kept]);
+    }
+
+    fn finds_what_encodes_left_behind(store: &mut dyn TestStore) {
+        let kept = add_missing(store, "clean-orphans", 0);
+        let next_to_kept = reencode::partial_path(&Path::new(&kept).with_extension("mkv"));
+        let elsewhere =
+            scratch_dir(&format!("clean-orphans-out-{}", store.kind())).join("film.mkv");
+        let temp_dir = scratch_dir(&format!("clean-orphans-temp-{}", store.kind()));
+        for path in [
+            &next_to_kept,
+            &reencode::partial_path(&elsewhere),
+            &temp_dir.join("in"),
+            &temp_dir.join("converting.mkv"),
+            &temp_dir.join("unrelated"),
+        ] {
+            fs::write(path, b"video").unwrap();
         }
+        let failed = JobRecord {
+            state: "failed".to_string(),
+            output_path: Some(elsewhere.to_str().unwrap().to_string()),
+            ..JobRecord::default()
+        };
+        let id = store.insert_job(&failed).unwrap();
+        store
+            .update_job(&JobRecord {
+                id,
+                ..failed.clone()
+            })
+            .unwrap();
+        let mut config = Config::load(store, None).unwrap();
+        config.clean.temp_dir = temp_dir.clone();
+        assert_eq!(clean(store, &config.clean).unwrap().orphans, 4);
+        assert!(next_to_kept.exists());
+
+        // An encode in progress, here retrying the failed one, keeps its files
+        let running = JobRecord {
+            state: "running".to_string(),
+            output_path: failed.output_path.clone(),
+            updated_at: Local::now(),
+            ..JobRecord::default()
+        };
+        let id = store.insert_job(&running).unwrap();
+        store.update_job(&JobRecord { id, ..running }).unwrap();
+        config.clean.remove_orphans = true;
+        assert_eq!(clean(store, &config.clean).unwrap().orphans, 1);
+        assert!(!next_to_kept.exists());
+        assert!(reencode::partial_path(&elsewhere).exists());
+        assert!(temp_dir.join("in").exists());
+
+        let finished = JobRecord {
+            id,
+            state: "succeeded".to_string(),
+            ..JobRecord::default()
+        };
+        store.update_job(&finished).unwrap();
+        assert_eq!(clean(store, &config.clean).unwrap().orphans, 3);
+        assert!(!reencode::partial_path(&elsewhere).exists());
+        assert_eq!(
+            fs::read_dir(&temp_dir).unwrap().count(),
+            1,
+            "only the unrelated file is left"
+        );
     }
 }
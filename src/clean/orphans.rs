@@ -0,0 +1,99 @@
+use super::CleanConfig;
+use crate::metrics;
+use crate::reencode;
+use crate::store::{JobRecord, Store};
+use chrono::Local;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Jobs that may be writing their scratch files and partial targets
+fn active_jobs(store: &mut dyn Store) -> Result<Vec<JobRecord>, Box<dyn Error>> {
+    let stale_before = Local::now() - chrono::Duration::from_std(reencode::STALE_AFTER)?;
+    let mut active = store.jobs_in_state("running")?;
+    active.extend(store.jobs_in_state("paused")?);
+    active.retain(|job| job.updated_at > stale_before);
+    Ok(active)
+}
+
+/// Whether an active job is using `path`. Asked right before each file is touched, as an
+/// encode may have started since the sweep did; encodes record their job before they
+/// write anything.
+fn in_use(
+    store: &mut dyn Store,
+    config: &CleanConfig,
+    path: &Path,
+) -> Result<bool, Box<dyn Error>> {
+    let active = active_jobs(store)?;
+    // Every encode on this machine uses the same scratch files
+    if !active.is_empty() && path.parent() == Some(config.temp_dir.as_path()) {
+        return Ok(true);
+    }
+    Ok(active
+        .iter()
+        .filter_map(|job| job.output_path.as_ref())
+        .any(|output| reencode::partial_path(Path::new(output)) == path))
+}
+
+/// Report, or with `remove_orphans` remove, the scratch files and partial targets that
+/// encodes left behind: those named like them in `TEMP_DIR`, those in `partials`, found
+/// next to known files, and those of failed and cancelled jobs. Files an active job is
+/// using are left alone. Returns how many there were.
+pub fn sweep(
+    store: &mut dyn Store,
+    config: &CleanConfig,
+    partials: Vec<PathBuf>,
+) -> Result<u64, Box<dyn Error>> {
+    let mut orphans: BTreeSet<PathBuf> = partials.into_iter().collect();
+    for state in ["failed", "cancelled"] {
+        for job in store.jobs_in_state(state)? {
+            if let Some(output) = job.output_path {
+                orphans.insert(reencode::partial_path(Path::new(&output)));
+            }
+        }
+    }
+    match fs::read_dir(&config.temp_dir) {
+        Ok(entries) => orphans.extend(
+            entries
+                .flatten()
+                .filter(|e| e.file_name().to_str().is_some_and(reencode::is_temp_file))
+                .map(|e| e.path()),
+        ),
+        Err(e) => warn!("Can't list {:?}: {}", config.temp_dir, e),
+    }
+
+    let mut found = 0;
+    let mut left: u64 = 0;
+    for path in orphans {
+        let bytes = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => continue,
+        };
+        if in_use(store, config, &path)? {
+            continue;
+        }
+        found += 1;
+        if !config.remove_orphans {
+            warn!(
+                "{:?} ({} bytes) was left behind by an encode; \
+                    set clean.remove_orphans to remove such files",
+                path, bytes
+            );
+            left += bytes;
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => info!(
+                "Removed {:?} ({} bytes), left behind by an encode",
+                path, bytes
+            ),
+            Err(e) => {
+                warn!("Failed to remove {:?}: {}", path, e);
+                left += bytes;
+            }
+        }
+    }
+    metrics::CLEAN_ORPHANED_BYTES.set(left as i64);
+    Ok(found)
+}
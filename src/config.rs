@@ -0,0 +1,473 @@
+mod file;
+
+use crate::clean::CleanConfig;
+use crate::reencode::ReencodeConfig;
+use crate::scan::ScanConfig;
+use crate::shutdown;
+use crate::store::{RootRecord, Store};
+use file::ConfigFile;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+lazy_static! {
+    static ref EMPTY: Map<String, Value> = Map::new();
+}
+
+/// A configuration value that is missing, malformed or not understood
+#[derive(Debug)]
+pub struct ConfigError {
+    /// Dotted path of the key, e.g. `reencode.profiles.default.crf`
+    pub key: String,
+    /// Where the value was set, e.g. `config` or `root /media/kids`
+    pub source: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (in {}): {}", self.key, self.source, self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+#[derive(Clone, Debug)]
+struct Layer<'a> {
+    source: String,
+    values: &'a Map<String, Value>,
+}
+
+/// One object of configuration with typed accessors. Several objects can be layered, later
+/// ones overriding earlier ones key by key; errors name the key and the layer it came from.
+/// A `null` value counts as unset.
+#[derive(Clone, Debug)]
+pub struct Fields<'a> {
+    name: String,
+    layers: Vec<Layer<'a>>,
+    /// How many of the bottom layers are the global settings a root's fields inherit
+    inherited: usize,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(name: &str) -> Fields<'a> {
+        Fields {
+            name: name.to_string(),
+            layers: Vec::new(),
+            inherited: 0,
+        }
+    }
+
+    /// Add `value`, which must be an object or null, over the existing layers
+    pub fn layer(
+        mut self,
+        source: &str,
+        value: Option<&'a Value>,
+    ) -> Result<Fields<'a>, ConfigError> {
+        let values = match value {
+            None | Some(Value::Null) => &*EMPTY,
+            Some(Value::Object(values)) => values,
+            Some(_) => {
+                return Err(ConfigError {
+                    key: self.name.clone(),
+                    source: source.to_string(),
+                    message: "must be an object".to_string(),
+                })
+            }
+        };
+        self.layers.push(Layer {
+            source: source.to_string(),
+            values,
+        });
+        Ok(self)
+    }
+
+    fn find(&self, key: &str) -> Option<(&Layer<'a>, &'a Value)> {
+        self.layers.iter().rev().find_map(|layer| {
+            layer
+                .values
+                .get(key)
+                .filter(|v| !v.is_null())
+                .map(|v| (layer, v))
+        })
+    }
+
+    /// An error about `key`, attributed to the layer that set it
+    pub fn error(&self, key: &str, message: impl fmt::Display) -> ConfigError {
+        let source = match self.find(key) {
+            Some((layer, _)) => layer.source.clone(),
+            None => self
+                .layers
+                .last()
+                .map(|l| l.source.clone())
+                .unwrap_or_default(),
+        };
+        ConfigError {
+            key: self.key(key),
+            source,
+            message: message.to_string(),
+        }
+    }
+
+    /// The dotted path of `key`
+    fn key(&self, key: &str) -> String {
+        if self.name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.name, key)
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'a Value> {
+        self.find(key).map(|(_, v)| v)
+    }
+
+    /// True if one of the layers over the inherited global settings sets `key` itself
+    pub fn overrides(&self, key: &str) -> bool {
+        self.layers[self.inherited..]
+            .iter()
+            .any(|l| l.values.get(key).is_some_and(|v| !v.is_null()))
+    }
+
+    /// Fail on any key not in `known`, which is most likely a typo
+    pub fn only(&self, known: &[&str]) -> Result<(), ConfigError> {
+        for layer in self.layers.iter() {
+            if let Some(key) = layer.values.keys().find(|k| !known.contains(&k.as_str())) {
+                return Err(ConfigError {
+                    key: self.key(key),
+                    source: layer.source.clone(),
+                    message: format!("unknown key; expected one of {}", known.join(", ")),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert `key` with `parse`, whose error becomes the message
+    pub fn parse<T>(
+        &self,
+        key: &str,
+        parse: impl FnOnce(&'a Value) -> Result<T, String>,
+    ) -> Result<Option<T>, ConfigError> {
+        self.get(key)
+            .map(|v| parse(v).map_err(|e| self.error(key, e)))
+            .transpose()
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        self.parse(key, |v| {
+            v.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| "must be a string".to_string())
+        })
+    }
+
+    pub fn int(&self, key: &str) -> Result<Option<i64>, ConfigError> {
+        self.parse(key, |v| {
+            v.as_i64().ok_or_else(|| "must be an integer".to_string())
+        })
+    }
+
+    /// A non-negative integer
+    pub fn count(&self, key: &str) -> Result<Option<u64>, ConfigError> {
+        self.parse(key, |v| {
+            v.as_u64()
+                .ok_or_else(|| "must be a non-negative integer".to_string())
+        })
+    }
+
+    pub fn float(&self, key: &str) -> Result<Option<f64>, ConfigError> {
+        self.parse(key, |v| {
+            v.as_f64().ok_or_else(|| "must be a number".to_string())
+        })
+    }
+
+    pub fn bool(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        self.parse(key, |v| {
+            v.as_bool()
+                .ok_or_else(|| "must be true or false".to_string())
+        })
+    }
+
+    /// Seconds, as a duration
+    pub fn seconds(&self, key: &str) -> Result<Option<Duration>, ConfigError> {
+        Ok(self.count(key)?.map(Duration::from_secs))
+    }
+
+    pub fn strings(&self, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+        self.parse(key, |v| {
+            v.as_array()
+                .and_then(|items| {
+                    items
+                        .iter()
+                        .map(|i| i.as_str().map(str::to_string))
+                        .collect()
+                })
+                .ok_or_else(|| "must be a list of strings".to_string())
+        })
+    }
+
+    /// The object at `key`, from the topmost layer that sets it. Nested objects replace
+    /// each other whole rather than being merged.
+    pub fn object(&self, key: &str) -> Result<Option<Fields<'a>>, ConfigError> {
+        match self.find(key) {
+            None => Ok(None),
+            Some((layer, value)) => Fields::new(&self.key(key))
+                .layer(&layer.source, Some(value))
+                .map(Some),
+        }
+    }
+
+    /// The keys set in any layer
+    pub fn keys(&self) -> Vec<&'a str> {
+        let mut keys: Vec<&str> = self
+            .layers
+            .iter()
+            .flat_map(|l| l.values.keys().map(String::as_str))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+/// One service's settings: its global ones, and the overrides of each active root
+pub struct Service<'a> {
+    pub global: Fields<'a>,
+    overrides: Vec<(String, Fields<'a>)>,
+}
+
+impl<'a> Service<'a> {
+    /// Check the global settings against `keys` and `root_keys`, and the overrides of
+    /// each root against `root_keys` alone
+    pub fn only(&self, keys: &[&str], root_keys: &[&str]) -> Result<(), ConfigError> {
+        let all: Vec<&str> = keys.iter().chain(root_keys.iter()).copied().collect();
+        self.global.only(&all)?;
+        for (_, overrides) in self.overrides.iter() {
+            overrides.only(root_keys)?;
+        }
+        Ok(())
+    }
+
+    /// Each active root, with its overrides layered over the global settings
+    pub fn roots(&self) -> Vec<(&str, Fields<'a>)> {
+        self.overrides
+            .iter()
+            .map(|(root, overrides)| {
+                let mut fields = self.global.clone();
+                fields.inherited = fields.layers.len();
+                fields.layers.extend(overrides.layers.iter().cloned());
+                (root.as_str(), fields)
+            })
+            .collect()
+    }
+}
+
+/// The raw configuration as stored in the database, and the file if there is one
+pub struct Sources {
+    /// The `config` of each service
+    pub services: HashMap<String, Value>,
+    pub roots: Vec<RootRecord>,
+    file: Option<ConfigFile>,
+}
+
+impl Sources {
+    /// Read the file first, so the roots it defines are in the database before it's read
+    pub fn load(store: &mut dyn Store, file: Option<&Path>) -> Result<Sources, Box<dyn Error>> {
+        let file = file.map(ConfigFile::read).transpose()?;
+        if let Some(file) = &file {
+            file.apply(store)?;
+        }
+        Ok(Sources {
+            services: store.service_configs()?.into_iter().collect(),
+            roots: store.roots()?,
+            file,
+        })
+    }
+
+    /// The file's settings for `service`, or with `root`, that root's overrides of them
+    pub fn file_settings(
+        &self,
+        service: &str,
+        root: Option<&str>,
+    ) -> Result<Option<&Value>, ConfigError> {
+        let file = match &self.file {
+            None => return Ok(None),
+            Some(file) => file,
+        };
+        Ok(match root {
+            None => file.service(service),
+            Some(root) => file
+                .roots()?
+                .into_iter()
+                .find(|r| r.root == root)
+                .and_then(|r| r.config.get(service)),
+        })
+    }
+
+    /// The database's settings for `name` with the file's layered on top, key by key
+    fn service<'a>(&'a self, name: &str) -> Result<Service<'a>, ConfigError> {
+        let mut global = Fields::new(name).layer("config", self.services.get(name))?;
+        let mut file_roots = Vec::new();
+        if let Some(file) = &self.file {
+            global = global.layer(&file.source, file.service(name))?;
+            file_roots = file.roots()?;
+        }
+        let mut overrides = Vec::new();
+        for root in self.roots.iter().filter(|r| r.active) {
+            let mut fields =
+                Fields::new(name).layer(&format!("root {}", root.root), root.config.get(name))?;
+            if let (Some(file), Some(entry)) =
+                (&self.file, file_roots.iter().find(|r| r.root == root.root))
+            {
+                fields = fields.layer(
+                    &format!("root {} in {}", root.root, file.source),
+                    entry.config.get(name),
+                )?;
+            }
+            overrides.push((root.root.clone(), fields));
+        }
+        Ok(Service { global, overrides })
+    }
+}
+
+/// Every module's settings, validated
+#[derive(Debug)]
+pub struct Config {
+    pub scan: ScanConfig,
+    pub clean: CleanConfig,
+    pub reencode: ReencodeConfig,
+}
+
+impl Config {
+    /// Load from the store and, if given, a TOML file whose values take precedence
+    pub fn load(store: &mut dyn Store, file: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+        Ok(Config::from_sources(&Sources::load(store, file)?)?)
+    }
+
+    pub fn from_sources(sources: &Sources) -> Result<Config, ConfigError> {
+        Ok(Config {
+            scan: ScanConfig::from_service(&sources.service("scan")?)?,
+            clean: CleanConfig::from_service(&sources.service("clean")?)?,
+            reencode: ReencodeConfig::from_service(&sources.service("reencode")?)?,
+        })
+    }
+}
+
+/// The current configuration, shared between the module threads
+pub struct Shared {
+    current: RwLock<Arc<Config>>,
+    /// Read again on every reload
+    file: Option<PathBuf>,
+}
+
+impl Shared {
+    pub fn new(config: Config, file: Option<&Path>) -> Shared {
+        Shared {
+            current: RwLock::new(Arc::new(config)),
+            file: file.map(Path::to_path_buf),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.current.read().expect("config lock poisoned").clone()
+    }
+
+    fn reload(&self, store: &mut dyn Store) {
+        match Config::load(store, self.file.as_deref()) {
+            Ok(config) => {
+                info!("Reloaded configuration");
+                *self.current.write().expect("config lock poisoned") = Arc::new(config);
+            }
+            Err(e) => error!("Keeping the previous configuration: {}", e),
+        }
+    }
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_hangup(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Reload `shared` whenever the store reports a change, or on SIGHUP (such as after
+/// editing the configuration file), until shutdown. A configuration that doesn't validate
+/// is logged and ignored.
+pub fn watch(shared: &Shared, store: &mut dyn Store) -> Result<(), Box<dyn Error>> {
+    let handler = handle_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+    }
+    while !shutdown::requested() {
+        let changed = store.wait_for_change(Duration::from_secs(1))?;
+        if changed || RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            shared.reload(store);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use serde_json::json;
+
+    #[test]
+    fn unset_values_take_their_defaults() {
+        let mut store = MemoryStore::new();
+        let config = Config::load(&mut store, None).unwrap();
+        assert_eq!(config.scan.interval, Duration::from_secs(3600));
+        assert_eq!(config.clean.interval, Duration::from_secs(3600));
+        assert_eq!(config.reencode.interval, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn errors_name_the_key_and_where_it_was_set() {
+        let mut store = MemoryStore::new();
+        store
+            .set_service_config(
+                "reencode",
+                &json!({"profiles": {"default": {"crf": "high"}}}),
+            )
+            .unwrap();
+        let error = Config::load(&mut store, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "reencode.profiles.default.crf (in config): must be an integer"
+        );
+
+        let mut store = MemoryStore::new();
+        store.add_root("/media/kids", true).unwrap();
+        store
+            .set_root_config("/media/kids", &json!({"reencode": {"order": "newest"}}))
+            .unwrap();
+        let error = Config::load(&mut store, None).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("reencode.order (in root /media/kids): unknown key"));
+    }
+
+    #[test]
+    fn inactive_roots_are_left_out() {
+        let mut store = MemoryStore::new();
+        store.add_root("/media/movies", true).unwrap();
+        store.add_root("/media/old", false).unwrap();
+        store
+            .set_root_config("/media/old", &json!({"reencode": {"crf": 20}}))
+            .unwrap();
+        let config = Config::load(&mut store, None).unwrap();
+        let roots: Vec<&str> = config
+            .reencode
+            .roots
+            .iter()
+            .map(|r| r.root.as_str())
+            .collect();
+        assert_eq!(roots, vec!["/media/movies"]);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::config::{ConfigError, Fields};
+use crate::store::Store;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// A root defined in the file
+pub struct FileRoot<'a> {
+    pub root: String,
+    active: bool,
+    /// The whole entry; its `scan`, `clean` and `reencode` tables are the root's overrides
+    pub config: &'a Value,
+}
+
+/// Settings from a TOML file, which take precedence over those in the database
+pub struct ConfigFile {
+    /// Where the file was read from, to say where a bad value came from
+    pub source: String,
+    value: Value,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<ConfigFile, Box<dyn Error>> {
+        let source = format!("{}", path.display());
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", &source, e))?;
+        let value: Value = toml::from_str(&text).map_err(|e| format!("{}: {}", &source, e))?;
+        let file = ConfigFile { source, value };
+        file.fields()?
+            .only(&["video_extensions", "roots", "scan", "clean", "reencode"])?;
+        file.roots()?;
+        Ok(file)
+    }
+
+    fn fields(&self) -> Result<Fields<'_>, ConfigError> {
+        Fields::new("").layer(&self.source, Some(&self.value))
+    }
+
+    /// The file's settings for `service`
+    pub fn service(&self, service: &str) -> Option<&Value> {
+        self.value.get(service)
+    }
+
+    pub fn roots(&self) -> Result<Vec<FileRoot<'_>>, ConfigError> {
+        let entries = match self.value.get("roots") {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(entries)) => entries,
+            Some(_) => return Err(self.fields()?.error("roots", "must be a list of tables")),
+        };
+        entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let fields =
+                    Fields::new(&format!("roots[{}]", i)).layer(&self.source, Some(entry))?;
+                fields.only(&["root", "active", "scan", "clean", "reencode"])?;
+                Ok(FileRoot {
+                    root: fields
+                        .string("root")?
+                        .ok_or_else(|| fields.error("root", "missing"))?,
+                    active: fields.bool("active")?.unwrap_or(true),
+                    config: entry,
+                })
+            })
+            .collect()
+    }
+
+    /// Make the `roots` and `video_extensions` tables agree with the file. Only what
+    /// differs is written, so an unchanged file doesn't look like a change to anyone.
+    pub fn apply(&self, store: &mut dyn Store) -> Result<(), Box<dyn Error>> {
+        let known = store.roots()?;
+        for root in self.roots()? {
+            if !known
+                .iter()
+                .any(|k| k.root == root.root && k.active == root.active)
+            {
+                store.add_root(&root.root, root.active)?;
+            }
+        }
+        if let Some(mut extensions) = self.fields()?.strings("video_extensions")? {
+            extensions.sort();
+            extensions.dedup();
+            let mut current = store.video_extensions()?;
+            current.sort();
+            if current != extensions {
+                store.set_video_extensions(&extensions)?;
+            }
+        }
+        Ok(())
+    }
+}
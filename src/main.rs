@@ -6,26 +6,46 @@ extern crate log;
 extern crate lazy_static;
 extern crate clap;
 extern crate crossbeam_utils;
+extern crate filetime;
+extern crate libc;
 extern crate pretty_env_logger;
+#[macro_use]
 extern crate prometheus;
 extern crate regex;
+extern crate rusqlite;
 extern crate serde_json;
 extern crate subprocess;
+extern crate toml;
+extern crate ureq;
+extern crate xattr;
 
+mod admin;
 mod clean;
+mod config;
+mod metrics;
 mod module;
 mod reencode;
+mod report;
 mod scan;
+mod shutdown;
+mod store;
 
-use clap::{parser::ValuesRef, Arg, ArgAction, Command};
+use clap::{parser::ValuesRef, value_parser, Arg, ArgAction, ArgMatches, Command};
+use config::Config;
 use module::Module;
-use postgres::Client;
+use std::error::Error;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use store::{Backend, Store};
 
 fn main() -> io::Result<()> {
     pretty_env_logger::init();
     info!("Starting main thread");
 
+    let root_arg = Arg::new("root")
+        .help("Work on this root's overrides instead of the global configuration")
+        .long("root");
     let args = Command::new("Video converter")
         .version("0.1")
         .author("Nathaniel Waisbrot")
@@ -34,49 +54,311 @@ fn main() -> io::Result<()> {
             Arg::new("username")
                 .help("Postgres username")
                 .long("username")
-                .required(true),
+                .required_unless_present("sqlite"),
         )
         .arg(
             Arg::new("password")
                 .help("Postgres password")
                 .long("password")
-                .required(true),
+                .required_unless_present("sqlite"),
         )
         .arg(
             Arg::new("host")
                 .help("Postgres hostname")
                 .long("host")
-                .required(true),
+                .required_unless_present("sqlite"),
         )
         .arg(
-            Arg::new("modules")
-                .help("Modules to activate")
-                .long("modules")
-                .required(false)
-                .value_delimiter(',')
-                .default_value("scan,clean,reencode"),
+            Arg::new("sqlite")
+                .help("Keep everything in this SQLite file instead of Postgres")
+                .long("sqlite")
+                .conflicts_with_all(["username", "password", "host"])
+                .value_parser(value_parser!(PathBuf)),
         )
         .arg(
-            Arg::new("loop")
-                .help("Continue to run forever?")
-                .long("loop")
-                .action(ArgAction::SetTrue)
-                .required(false),
+            Arg::new("config")
+                .help("TOML file of settings that take precedence over the config table")
+                .long("config")
+                .value_parser(value_parser!(PathBuf)),
         )
-        .get_matches();
-
-    // Postgres setup
-    let mut postgres_config = Client::configure();
-    postgres_config
-        .user(
-            args.get_one::<String>("username")
-                .expect("missing username"),
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("run")
+                .about("Run modules in the background")
+                .arg(
+                    Arg::new("modules")
+                        .help("Modules to activate")
+                        .long("modules")
+                        .required(false)
+                        .value_delimiter(',')
+                        .default_value("scan,clean,reencode"),
+                )
+                .arg(
+                    Arg::new("metrics-address")
+                        .help("Address to serve Prometheus metrics on, e.g. 0.0.0.0:9100")
+                        .long("metrics-address")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("loop")
+                        .help("Continue to run forever?")
+                        .long("loop")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("shutdown-timeout")
+                        .help(
+                            "Seconds to cancel work and release claims after SIGTERM [default: 8]",
+                        )
+                        .long("shutdown-timeout")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("Scan a file or directory now")
+                .arg(Arg::new("path").required(true)),
+        )
+        .subcommand(
+            Command::new("probe")
+                .about("Print what ffprobe finds in a file")
+                .arg(Arg::new("file").required(true)),
+        )
+        .subcommand(
+            Command::new("reencode")
+                .about("Reencode a file now")
+                .arg(Arg::new("file").required(true)),
+        )
+        .subcommand(
+            Command::new("queue")
+                .about("Inspect the reencode queue")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List pending and claimed files")
+                        .arg(
+                            Arg::new("limit")
+                                .help("How many pending files to show")
+                                .long("limit")
+                                .value_parser(value_parser!(i64))
+                                .default_value("20"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("bump")
+                        .about("Reencode a file, or everything in a directory, before the rest")
+                        .arg(Arg::new("path").required(true))
+                        .arg(
+                            Arg::new("priority")
+                                .help("Higher goes first; 0 puts it back in the normal order")
+                                .long("priority")
+                                .value_parser(value_parser!(i32))
+                                .default_value("100"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("retry")
+                        .about("Requeue the file of a failed or skipped job")
+                        .arg(
+                            Arg::new("id")
+                                .help("Job id")
+                                .required(true)
+                                .value_parser(value_parser!(i64)),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("roots")
+                .about("Manage the directories that are scanned")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("add")
+                        .arg(Arg::new("root").required(true))
+                        .arg(
+                            Arg::new("inactive")
+                                .help("Add the root without scanning it yet")
+                                .long("inactive")
+                                .action(ArgAction::SetTrue),
+                        ),
+                )
+                .subcommand(Command::new("remove").arg(Arg::new("root").required(true)))
+                .subcommand(Command::new("list")),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Read and change module configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("get")
+                        .arg(Arg::new("service").required(true))
+                        .arg(Arg::new("key").required(false))
+                        .arg(root_arg.clone()),
+                )
+                .subcommand(
+                    Command::new("set")
+                        .arg(Arg::new("service").required(true))
+                        .arg(Arg::new("key").required(true))
+                        .arg(
+                            Arg::new("value")
+                                .required(true)
+                                .help("A JSON value or a string"),
+                        )
+                        .arg(root_arg),
+                ),
+        )
+        .subcommand(
+            Command::new("damaged")
+                .about("List the files the health check found damaged")
+                .arg(
+                    Arg::new("lines")
+                        .help("How many lines of ffmpeg's errors to show for each file")
+                        .long("lines")
+                        .value_parser(value_parser!(usize))
+                        .default_value("3"),
+                ),
         )
-        .password(
-            args.get_one::<String>("password")
-                .expect("missing password"),
+        .subcommand(
+            Command::new("report")
+                .about("Print library statistics and space savings")
+                .arg(
+                    Arg::new("format")
+                        .help("Output format")
+                        .long("format")
+                        .value_parser(["table", "json"])
+                        .default_value("table"),
+                ),
         )
-        .host(args.get_one::<String>("host").expect("missing hostname"));
+        .get_matches();
+
+    let backend = match args.get_one::<PathBuf>("sqlite") {
+        Some(path) => Backend::Sqlite(path.clone()),
+        None => {
+            let mut postgres_config = postgres::Client::configure();
+            postgres_config
+                .user(
+                    args.get_one::<String>("username")
+                        .expect("missing username"),
+                )
+                .password(
+                    args.get_one::<String>("password")
+                        .expect("missing password"),
+                )
+                .host(args.get_one::<String>("host").expect("missing hostname"));
+            Backend::Postgres(Box::new(postgres_config))
+        }
+    };
+
+    let config_file = args.get_one::<PathBuf>("config").map(PathBuf::as_path);
+    match args.subcommand() {
+        Some(("run", run_args)) => {
+            shutdown::install(
+                run_args
+                    .get_one::<u64>("shutdown-timeout")
+                    .map(|s| Duration::from_secs(*s))
+                    .unwrap_or(shutdown::DEFAULT_TIMEOUT),
+            );
+            run(&backend, run_args, config_file)
+        }
+        Some((name, sub_args)) => {
+            // Long-running commands clean up after themselves when interrupted
+            if name == "scan" || name == "reencode" {
+                shutdown::install(shutdown::DEFAULT_TIMEOUT);
+            }
+            let mut store = backend.connect().unwrap();
+            if let Err(e) = command(store.as_mut(), name, sub_args, config_file) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        None => unreachable!("a subcommand is required"),
+    }
+}
+
+fn required_arg<'a>(args: &'a ArgMatches, name: &str) -> &'a str {
+    args.get_one::<String>(name)
+        .unwrap_or_else(|| panic!("missing {}", name))
+}
+
+/// Run one of the one-off subcommands
+fn command(
+    store: &mut dyn Store,
+    name: &str,
+    args: &ArgMatches,
+    config_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    match (name, args.subcommand()) {
+        ("scan", _) => {
+            let config = Config::load(store, config_file)?;
+            scan::scan_path(Path::new(required_arg(args, "path")), &config, store)
+        }
+        ("probe", _) => {
+            let info = scan::ffprobe::probe(&required_arg(args, "file").to_string())?;
+            println!("{}", serde_json::to_string_pretty(&info.summary())?);
+            Ok(())
+        }
+        ("reencode", _) => {
+            let config = Config::load(store, config_file)?;
+            reencode::reencode_path(
+                store,
+                &config.reencode,
+                Path::new(required_arg(args, "file")),
+            )
+        }
+        ("queue", Some(("list", sub))) => {
+            let config = Config::load(store, config_file)?;
+            admin::queue_list(
+                store,
+                &config,
+                *sub.get_one::<i64>("limit").expect("missing limit"),
+            )
+        }
+        ("queue", Some(("bump", sub))) => admin::queue_bump(
+            store,
+            required_arg(sub, "path"),
+            *sub.get_one::<i32>("priority").expect("missing priority"),
+        ),
+        ("queue", Some(("retry", sub))) => {
+            admin::queue_retry(store, *sub.get_one::<i64>("id").expect("missing id"))
+        }
+        ("roots", Some(("add", sub))) => {
+            admin::roots_add(store, required_arg(sub, "root"), !sub.get_flag("inactive"))
+        }
+        ("roots", Some(("remove", sub))) => admin::roots_remove(store, required_arg(sub, "root")),
+        ("roots", Some(("list", _))) => admin::roots_list(store),
+        ("config", Some(("get", sub))) => admin::config_get(
+            store,
+            required_arg(sub, "service"),
+            sub.get_one::<String>("key").map(|k| k.as_str()),
+            sub.get_one::<String>("root").map(|r| r.as_str()),
+            config_file,
+        ),
+        ("config", Some(("set", sub))) => admin::config_set(
+            store,
+            required_arg(sub, "service"),
+            required_arg(sub, "key"),
+            required_arg(sub, "value"),
+            sub.get_one::<String>("root").map(|r| r.as_str()),
+            config_file,
+        ),
+        ("damaged", _) => admin::damaged_list(
+            store,
+            *args.get_one::<usize>("lines").expect("missing lines"),
+        ),
+        ("report", _) => {
+            let config = Config::load(store, config_file)?;
+            report::report(store, &config, required_arg(args, "format"))
+        }
+        _ => unreachable!("unknown subcommand {}", name),
+    }
+}
+
+/// Start the requested modules, each on its own thread with its own connection
+fn run(backend: &Backend, args: &ArgMatches, config_file: Option<&Path>) -> io::Result<()> {
+    if let Some(address) = args.get_one::<String>("metrics-address") {
+        metrics::serve(address)?;
+    }
 
     // Modules
     let modules = args
@@ -87,6 +369,17 @@ fn main() -> io::Result<()> {
         modules.clone().any(|x| x == target)
     }
 
+    // Loaded once up front, so a broken configuration stops us before anything starts
+    let mut config_store = backend.connect().unwrap();
+    let shared = match Config::load(config_store.as_mut(), config_file) {
+        Ok(config) => config::Shared::new(config, config_file),
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let shared = &shared;
+
     let do_loop = args.get_flag("loop");
     let all_modules: Vec<&dyn Module> =
         vec![&scan::Scan {}, &clean::Clean {}, &reencode::Reencode {}];
@@ -96,16 +389,28 @@ fn main() -> io::Result<()> {
             let name = m.module_name();
             debug!("Checking if we should start a thread for {}", &name);
             if modules_contains(&modules, name) {
-                debug!("Connecting to postgres");
-                let mut connection = postgres_config.connect(postgres::NoTls).unwrap();
+                debug!("Connecting to the store");
+                let mut store = backend.connect().unwrap();
                 info!("Starting thread {}", &name);
                 scope
                     .builder()
                     .name(name.to_string())
-                    .spawn(move |_| m.module_loop(&mut connection, do_loop))
+                    .spawn(move |_| m.module_loop(store.as_mut(), shared, do_loop))
                     .unwrap();
             }
         }
+        // Only long-running modules see a changed configuration
+        if do_loop {
+            scope
+                .builder()
+                .name("config".to_string())
+                .spawn(move |_| {
+                    if let Err(e) = config::watch(shared, config_store.as_mut()) {
+                        error!("Stopped watching for configuration changes: {}", e);
+                    }
+                })
+                .unwrap();
+        }
         info!("All threads started")
     })
     .unwrap();
@@ -1,6 +1,75 @@
-use prometheus::{self, Encoder, IntCounterVec, TextEncoder};
+use prometheus::{self, Encoder, Gauge, IntCounterVec, IntGauge, TextEncoder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
 
 lazy_static! {
-    static ref FILE_COUNTER: IntCounterVec =
+    pub static ref FILE_COUNTER: IntCounterVec =
         register_int_counter_vec!("file_total", "Files procecssed", &["stage"]).unwrap();
+    pub static ref ENCODE_PERCENT: Gauge =
+        register_gauge!("reencode_percent", "Percent done of the current encode").unwrap();
+    pub static ref ENCODE_FPS: Gauge =
+        register_gauge!("reencode_fps", "Frames per second of the current encode").unwrap();
+    pub static ref ENCODE_SPEED: Gauge = register_gauge!(
+        "reencode_speed",
+        "Speed of the current encode as a multiple of realtime"
+    )
+    .unwrap();
+    pub static ref ENCODE_ETA: Gauge = register_gauge!(
+        "reencode_eta_seconds",
+        "Estimated seconds until the current encode finishes"
+    )
+    .unwrap();
+    pub static ref CLEAN_BLOCKED_ROOTS: IntGauge = register_int_gauge!(
+        "clean_blocked_roots",
+        "Roots whose missing files the last clean refused to remove"
+    )
+    .unwrap();
+    pub static ref CLEAN_ORPHANED_BYTES: IntGauge = register_int_gauge!(
+        "clean_orphaned_bytes",
+        "Bytes in files left behind by encodes that the last clean found and kept"
+    )
+    .unwrap();
+}
+
+/// Serve the text exposition format to anything that connects to `address`
+pub fn serve(address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving metrics on {}", address);
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = respond(stream) {
+                            debug!("Failed to send metrics: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Metrics connection failed: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    // Whatever was asked for, the answer is the metrics; just consume the request headers
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut body)
+        .map_err(io::Error::other)?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    )?;
+    stream.write_all(&body)
 }
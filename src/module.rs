@@ -1,5 +1,6 @@
-use postgres::Client;
-use std::thread::sleep;
+use crate::config::{Config, Shared};
+use crate::shutdown;
+use crate::store::Store;
 use std::time::Duration;
 
 pub trait Module
@@ -7,42 +8,20 @@ where
     Self: std::marker::Sync,
 {
     fn module_name(&self) -> &str;
-    fn module_iteration(&self, connection: &mut Client);
-    fn module_loop(&self, connection: &mut Client, do_loop: bool) {
+    /// How long to wait between iterations
+    fn interval(&self, config: &Config) -> Duration;
+    fn module_iteration(&self, store: &mut dyn Store, config: &Config);
+    fn module_loop(&self, store: &mut dyn Store, config: &Shared, do_loop: bool) {
         loop {
-            let interval_s = self.config_int(connection, "interval");
-            let interval = Duration::from_secs(interval_s as u64);
-            self.module_iteration(connection);
-            if do_loop {
-                sleep(interval);
-            } else {
+            // Picks up a reloaded configuration from one iteration to the next
+            let config = config.current();
+            self.module_iteration(store, &config);
+            if do_loop && !shutdown::requested() {
+                shutdown::sleep(self.interval(&config));
+            }
+            if !do_loop || shutdown::requested() {
                 break;
             }
         }
     }
-    fn config_string(&self, connection: &mut Client, key: &str) -> String {
-        let name = self.module_name();
-        let s: String = connection
-            .query(
-                "SELECT (config->$1)::text FROM config WHERE service = $2",
-                &[&key, &name],
-            )
-            .expect("Query failed")
-            .first()
-            .expect("Did not get any rows from query")
-            .get(0);
-        s.trim_matches('"').to_string()
-    }
-    fn config_int(&self, connection: &mut Client, key: &str) -> i32 {
-        let name = self.module_name();
-        connection
-            .query(
-                "SELECT (config->$1)::int FROM config WHERE service = $2",
-                &[&key, &name],
-            )
-            .expect("Query failed")
-            .first()
-            .expect("Did not get any rows from query")
-            .get(0)
-    }
 }
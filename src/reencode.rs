@@ -1,100 +1,983 @@
+mod busy;
+mod ffmpeg;
+mod hdr;
+mod job;
+mod metadata;
+mod quality;
+mod scale;
+mod schedule;
+
+use crate::config::{Config, ConfigError, Fields, Service};
+use crate::metrics;
+use crate::scan::ffprobe;
 use crate::scan::file::ScannedFile;
-use postgres::Client;
+use crate::scan::settle::Settle;
+use crate::shutdown;
+use crate::store::{Candidate, QueueOrder, Store, Target};
+use ffmpeg::{Control, Event, Finished};
+use job::Job;
+use metadata::Preserve;
+use quality::QualityCheck;
+use scale::Scaling;
+use schedule::Schedule;
+use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use subprocess::Exec;
-use subprocess::Redirection;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+/// How much `copy` writes between checks for a shutdown
+const COPY_CHUNK: usize = 8 * 1024 * 1024;
+/// Where an encode keeps its copy of the source (`in`) and ffmpeg's output
+/// (`converting.<ext>`) until the output is installed
+pub const TEMP_DIR: &str = "/tmp";
+
+/// A running or paused job that hasn't saved itself for this long died with its process
+pub const STALE_AFTER: Duration = Duration::from_secs(3600);
+
+/// Whether `name` in `TEMP_DIR` is one of an encode's scratch files
+pub fn is_temp_file(name: &str) -> bool {
+    name == "in" || name.starts_with("converting.")
+}
+
+/// The scratch files of an encode with `settings`: the copy of the source, and
+/// ffmpeg's output
+fn scratch_paths(settings: &EncodeSettings) -> (PathBuf, PathBuf) {
+    let temp_dir = Path::new(TEMP_DIR);
+    (
+        temp_dir.join("in"),
+        temp_dir.join(format!("converting.{}", settings.target_extension)),
+    )
+}
+
+/// Where `install` writes the target before renaming it into place
+pub fn partial_path(target_path: &Path) -> PathBuf {
+    let file_name = target_path
+        .file_name()
+        .expect("target has no file name")
+        .to_string_lossy();
+    target_path.with_file_name(format!(".{}.reencoding", file_name))
+}
+
+/// Whether `name` is that of a target being installed
+pub fn is_partial(name: &str) -> bool {
+    name.len() > ".reencoding".len() + 1 && name.starts_with('.') && name.ends_with(".reencoding")
+}
+
+/// Codec options applied to every encode that uses the profile
+#[derive(Debug)]
+struct EncodingProfile {
+    name: String,
+    audio_codec: String,
+    crf: Option<i64>,
+    preset: Option<String>,
+    scaling: Scaling,
+    quality: Option<QualityCheck>,
+}
+
+impl EncodingProfile {
+    fn from_fields(name: &str, fields: Option<Fields>) -> Result<EncodingProfile, ConfigError> {
+        let fields = fields.unwrap_or_else(|| Fields::new(name));
+        let keys: Vec<&str> = ["audio_codec", "crf", "preset"]
+            .iter()
+            .chain(scale::KEYS.iter())
+            .chain(quality::KEYS.iter())
+            .copied()
+            .collect();
+        fields.only(&keys)?;
+        let crf = fields.int("crf")?;
+        Ok(EncodingProfile {
+            name: name.to_string(),
+            audio_codec: fields.string("audio_codec")?.unwrap_or("aac".to_string()),
+            crf,
+            preset: fields.string("preset")?,
+            scaling: Scaling::from_fields(&fields)?,
+            quality: QualityCheck::from_fields(&fields, crf)?,
+        })
+    }
+}
+
+/// What to do when the target path of an encode is already taken by another file
+#[derive(Debug, PartialEq)]
+enum CollisionPolicy {
+    /// Leave the existing file alone and do not encode the source
+    Skip,
+    /// Write the encode to `name.1.ext`, `name.2.ext`, ... instead
+    Suffix,
+    /// Overwrite the existing file only if it was encoded from this same source
+    ReplacePriorEncode,
+}
+
+impl CollisionPolicy {
+    fn from_name(name: &str) -> Result<CollisionPolicy, String> {
+        match name {
+            "skip" => Ok(CollisionPolicy::Skip),
+            "suffix" => Ok(CollisionPolicy::Suffix),
+            "replace_prior_encode" => Ok(CollisionPolicy::ReplacePriorEncode),
+            _ => Err(format!(
+                "unknown policy {}; expected skip, suffix or replace_prior_encode",
+                name
+            )),
+        }
+    }
+}
+
+/// The reencode settings of one root, after merging it over the global `reencode` config
+#[derive(Debug)]
+pub(crate) struct EncodeSettings {
+    pub(crate) root: String,
+    pub(crate) target_codec: String,
+    pub(crate) target_extension: String,
+    profile: EncodingProfile,
+    priority: i32,
+    pub(crate) enabled: bool,
+    delete_originals: bool,
+    output_root: Option<PathBuf>,
+    on_collision: CollisionPolicy,
+    preserve: Preserve,
+    settle: Settle,
+}
+
+impl EncodeSettings {
+    /// The keys read by `from_fields`
+    const KEYS: [&'static str; 12] = [
+        "target_codec",
+        "target_extension",
+        "profile",
+        "profiles",
+        "priority",
+        "enabled",
+        "delete_originals",
+        "output_root",
+        "on_collision",
+        "preserve",
+        "settle_minutes",
+        "check_open_files",
+    ];
+
+    fn from_fields(root: &str, fields: &Fields) -> Result<EncodeSettings, ConfigError> {
+        let profile_name = fields.string("profile")?.unwrap_or("default".to_string());
+        let profiles = fields.object("profiles")?;
+        let profile = match &profiles {
+            None => None,
+            Some(profiles) => profiles.object(&profile_name)?,
+        };
+        if profile.is_none() && profiles.is_some() && profile_name != "default" {
+            return Err(fields.error("profile", format!("unknown profile {}", profile_name)));
+        }
+        let output_root = fields.string("output_root")?.map(PathBuf::from);
+        let priority = fields.int("priority")?.unwrap_or(0);
+        Ok(EncodeSettings {
+            root: root.to_string(),
+            target_codec: fields.string("target_codec")?.unwrap_or("hevc".to_string()),
+            target_extension: fields
+                .string("target_extension")?
+                .unwrap_or("mkv".to_string()),
+            profile: EncodingProfile::from_fields(&profile_name, profile)?,
+            priority: i32::try_from(priority)
+                .map_err(|_| fields.error("priority", "out of range"))?,
+            enabled: fields.bool("enabled")?.unwrap_or(true),
+            // Originals are kept by default when the encode lands in a separate tree
+            delete_originals: fields
+                .bool("delete_originals")?
+                .unwrap_or(output_root.is_none()),
+            output_root,
+            on_collision: fields
+                .parse("on_collision", |v| {
+                    v.as_str()
+                        .ok_or_else(|| "must be a string".to_string())
+                        .and_then(CollisionPolicy::from_name)
+                })?
+                .unwrap_or(CollisionPolicy::Skip),
+            preserve: Preserve::from_fields(fields.object("preserve")?)?,
+            settle: Settle::from_fields(fields)?,
+        })
+    }
+
+    /// Where the encoded copy of `source_path` is written
+    fn target_path(&self, source_path: &Path) -> PathBuf {
+        match &self.output_root {
+            None => source_path.with_extension(&self.target_extension),
+            Some(output_root) => {
+                let relative = source_path
+                    .strip_prefix(&self.root)
+                    .expect("source is not inside its root");
+                output_root
+                    .join(relative)
+                    .with_extension(&self.target_extension)
+            }
+        }
+    }
+}
+
+/// Pick the path to write the encode of `source_path` to, or `None` if it should be skipped
+fn resolve_collision(
+    store: &mut dyn Store,
+    settings: &EncodeSettings,
+    source_path: &Path,
+    target_path: PathBuf,
+) -> Option<PathBuf> {
+    // Replacing the source itself is the point of an in-place reencode
+    if target_path == source_path || !target_path.exists() {
+        return Some(target_path);
+    }
+    match settings.on_collision {
+        CollisionPolicy::Skip => None,
+        CollisionPolicy::Suffix => {
+            let stem = target_path
+                .file_stem()
+                .expect("target has no file name")
+                .to_string_lossy()
+                .to_string();
+            (1..)
+                .map(|i| {
+                    target_path
+                        .with_file_name(format!("{}.{}.{}", &stem, i, &settings.target_extension))
+                })
+                .find(|candidate| !candidate.exists())
+        }
+        CollisionPolicy::ReplacePriorEncode => {
+            let target_path_s = format!("{}", target_path.display());
+            let source_path_s = format!("{}", source_path.display());
+            let prior = store.encoded_from(&target_path_s).unwrap();
+            if prior.as_deref() == Some(source_path_s.as_str()) {
+                Some(target_path)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Copy `from` to `to` a chunk at a time, giving up with a "shutting down" error once a
+/// shutdown is asked for; a large file takes longer to copy than shutdown waits
+fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    let mut reader = fs::File::open(from)?;
+    let mut writer = fs::File::create(to)?;
+    let mut buffer = vec![0; COPY_CHUNK];
+    loop {
+        if shutdown::requested() {
+            return Err(io::Error::other("shutting down"));
+        }
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read])?;
+    }
+    writer.set_permissions(reader.metadata()?.permissions())
+}
+
+/// Move `temp_path` to `target_path` via a scratch file in the target's directory, so
+/// the target is replaced atomically and never seen half-written. The scratch file
+/// takes on the attributes of `source_path` before it is renamed into place.
+fn install(
+    temp_path: &Path,
+    source_path: &Path,
+    target_path: &Path,
+    preserve: &Preserve,
+) -> io::Result<()> {
+    let parent = target_path.parent().expect("target has no directory");
+    fs::create_dir_all(parent)?;
+    let partial_path = partial_path(target_path);
+    info!("cp {:?} {:?}", temp_path, &partial_path);
+    if let Err(e) = copy(temp_path, &partial_path) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(e);
+    }
+    metadata::copy_metadata(source_path, &partial_path, preserve);
+    info!("mv {:?} {:?}", &partial_path, target_path);
+    fs::rename(&partial_path, target_path)
+}
+
+/// Run an ffmpeg `command` for `job`, pausing it while the schedule blocks encoding and
+/// cancelling it on shutdown. Only the encode itself (`encoding`) reports the job's
+/// progress; other runs just keep the job from looking abandoned.
+fn supervise(
+    store: &mut dyn Store,
+    schedule: &Schedule,
+    job: &mut Job,
+    command: Exec,
+    encoding: bool,
+) -> Result<Finished, Box<dyn Error>> {
+    let mut paused = false;
+    ffmpeg::run(command, |event| {
+        match event {
+            Event::Progress(progress) if encoding => job.progress(store, progress),
+            // Paused or not, the job has to keep showing it is alive
+            _ => job.touch(store),
+        }
+        if shutdown::requested() {
+            return Control::Cancel;
+        }
+        let blocked = schedule.blocked();
+        if paused != blocked.is_some() {
+            paused = blocked.is_some();
+            if let Some(reason) = blocked {
+                info!("Pausing job {}: {}", job.id, reason);
+                job.state(store, "paused");
+            } else {
+                info!("Resuming job {}", job.id);
+                job.state(store, "running");
+            }
+        }
+        if paused {
+            Control::Pause
+        } else {
+            Control::Run
+        }
+    })
+}
+
+/// Give up on `job` at shutdown, removing its `scratch` files and putting its path back
+/// in the queue
+fn cancel(
+    store: &mut dyn Store,
+    job: &mut Job,
+    id: i64,
+    scratch: &[&Path],
+    reason: &str,
+) -> Result<(), Box<dyn Error>> {
+    info!(
+        "Cancelled job {}; {} goes back in the queue",
+        job.id,
+        job.source_path()
+    );
+    for path in scratch {
+        let _ = fs::remove_file(path);
+    }
+    store.release(id)?;
+    job.finish(store, "cancelled", reason);
+    Ok(())
+}
+
+/// Encode one claimed file, recording the attempt in `job`
+fn reencode(
+    store: &mut dyn Store,
+    settings: &EncodeSettings,
+    schedule: &Schedule,
+    job: &mut Job,
+    id: i64,
+    source_path_s: &str,
+    original_bytes: i64,
+) -> Result<(), Box<dyn Error>> {
+    let source_path = Path::new(source_path_s);
+    let (source_temp_path, temp_path) = scratch_paths(settings);
+    let source_temp_path = &source_temp_path;
+    let target_path = match resolve_collision(
+        store,
+        settings,
+        source_path,
+        settings.target_path(source_path),
+    ) {
+        Some(target_path) => target_path,
+        None => {
+            let message = format!("{:?} already exists", settings.target_path(source_path));
+            warn!("Not reencoding {:?}: {}", source_path, &message);
+            job.finish(store, "skipped", &message);
+            return Ok(());
+        }
+    };
+    let source_info = ffprobe::probe(&source_path_s.to_string())?;
+    job.source(store, &source_info, original_bytes);
+    if let Some(message) = hdr::unsupported(&source_info) {
+        warn!("Not reencoding {:?}: {}", source_path, &message);
+        job.finish(store, "skipped", &message);
+        return Ok(());
+    }
+    info!("Copy {:?} to temp", &source_path);
+    copy(source_path, source_temp_path).map_err(|e| {
+        format!(
+            "failed to copy {:?} to {:?}: {}",
+            source_path, source_temp_path, e
+        )
+    })?;
+    // Each retry after a failed quality check lowers the crf by the profile's step
+    let mut crf = settings.profile.crf;
+    let mut retries = settings.profile.quality.as_ref().map_or(0, |q| q.retries);
+    let output_info = loop {
+        info!("Converting {:?} as job {}", &source_path, job.id);
+        let mut command = schedule
+            .command("ffmpeg")
+            .arg("-y")
+            .arg("-loglevel")
+            .arg("warning")
+            .arg("-i")
+            .arg(source_temp_path)
+            .arg("-c:v")
+            .arg(&settings.target_codec);
+        if let Some(crf) = crf {
+            command = command.arg("-crf").arg(crf.to_string());
+        }
+        if let Some(preset) = &settings.profile.preset {
+            command = command.arg("-preset").arg(preset);
+        }
+        // Scan's record of the size is what put the file in the queue
+        let known = store.file(source_path_s)?;
+        if let Some(filters) = settings.profile.scaling.filters(
+            known.as_ref().and_then(|f| f.width).or(source_info.width),
+            known.as_ref().and_then(|f| f.height).or(source_info.height),
+            source_info.frame_rate,
+        ) {
+            command = command.arg("-vf").arg(filters);
+        }
+        let command = command
+            .args(&hdr::args(&source_info, &settings.target_codec))
+            .arg("-c:a")
+            .arg(&settings.profile.audio_codec)
+            .arg("-hide_banner")
+            .arg("-nostats")
+            .arg("-progress")
+            .arg("pipe:1")
+            .arg(&temp_path);
+        job.command(
+            store,
+            &command.to_cmdline_lossy(),
+            &settings.profile.name,
+            &target_path,
+        );
+        let finished = supervise(store, schedule, job, command, true)?;
+        job.stderr(&finished.stderr);
+        job.exit_status(&finished.status);
+        if finished.cancelled {
+            let scratch = [source_temp_path.as_path(), &temp_path];
+            return cancel(store, job, id, &scratch, "shut down during the encode");
+        }
+        if !finished.status.success() {
+            warn!("ffmpeg failed: {}", &finished.stderr);
+            let _ = fs::remove_file(source_temp_path);
+            let _ = fs::remove_file(&temp_path);
+            job.finish(store, "failed", "ffmpeg failed");
+            return Ok(());
+        }
+        let output_info = ffprobe::probe(&format!("{}", temp_path.display()))?;
+        let check = match &settings.profile.quality {
+            Some(check) => check,
+            None => break output_info,
+        };
+        let measured = check.measure(
+            schedule,
+            (source_temp_path, &source_info),
+            (&temp_path, &output_info),
+            |command| supervise(store, schedule, job, command, false),
+        );
+        let score = match measured {
+            Ok(Some(score)) => score,
+            Ok(None) => {
+                let scratch = [source_temp_path.as_path(), &temp_path];
+                return cancel(
+                    store,
+                    job,
+                    id,
+                    &scratch,
+                    "shut down during the quality check",
+                );
+            }
+            Err(e) => {
+                warn!("Quality check of job {} failed: {}", job.id, e);
+                let _ = fs::remove_file(source_temp_path);
+                let _ = fs::remove_file(&temp_path);
+                job.finish(store, "failed", &format!("quality check failed: {}", e));
+                return Ok(());
+            }
+        };
+        job.quality(store, check.metric.name(), score);
+        if score >= check.min_quality {
+            break output_info;
+        }
+        let message = format!(
+            "{} {} is below min_quality {}",
+            check.metric.name(),
+            score,
+            check.min_quality
+        );
+        if let Some(previous) = crf.filter(|_| retries > 0) {
+            retries -= 1;
+            let lower = (previous - check.crf_step).max(0);
+            info!(
+                "Job {}: {}; encoding again with crf {}",
+                job.id, message, lower
+            );
+            crf = Some(lower);
+            continue;
+        }
+        warn!("Rejecting job {}: {}", job.id, message);
+        let _ = fs::remove_file(source_temp_path);
+        let _ = fs::remove_file(&temp_path);
+        job.finish(store, "failed", &message);
+        return Ok(());
+    };
+    install(&temp_path, source_path, &target_path, &settings.preserve)
+        .map_err(|e| format!("failed to write {:?}: {}", &target_path, e))?;
+    let new_file = ScannedFile::new(&target_path, store)?;
+    let new_bytes = new_file.record.bytes;
+    info!(
+        "Bytes {:?} -> {:?} = {:?}",
+        original_bytes,
+        new_bytes,
+        new_bytes - original_bytes
+    );
+    job.output(&output_info, new_bytes);
+    let _store_result = new_file.store(store);
+    metrics::FILE_COUNTER.with_label_values(&["reencode"]).inc();
+    let target_path_s = format!("{}", target_path.display());
+    store.set_encoded_from(&target_path_s, source_path_s)?;
+    if source_path != target_path {
+        if settings.delete_originals {
+            info!("rm {:?}", &source_path);
+            fs::remove_file(source_path)
+                .map_err(|e| format!("failed to remove file {:?}: {}", source_path, e))?;
+        } else {
+            debug!("Keeping original {:?}", &source_path);
+            store.keep_original(id, &target_path_s)?;
+        }
+    }
+    fs::remove_file(source_temp_path)
+        .map_err(|e| format!("failed to remove file {:?}: {}", source_temp_path, e))?;
+    job.finish(store, "succeeded", "");
+    Ok(())
+}
+
+/// Settings of the reencode module
+#[derive(Debug)]
+pub struct ReencodeConfig {
+    pub interval: Duration,
+    order: QueueOrder,
+    schedule: Schedule,
+    /// Every active root, including those that don't allow reencoding: their files stay
+    /// out of the queue even beneath one that does
+    pub(crate) roots: Vec<EncodeSettings>,
+}
+
+impl ReencodeConfig {
+    pub fn from_service(service: &Service) -> Result<ReencodeConfig, ConfigError> {
+        let keys: Vec<&str> = ["interval", "order"]
+            .iter()
+            .chain(Schedule::KEYS.iter())
+            .copied()
+            .collect();
+        service.only(&keys, &EncodeSettings::KEYS)?;
+        let global = &service.global;
+        // Settings of roots without overrides still have to make sense on their own
+        EncodeSettings::from_fields("", global)?;
+        let mut roots = Vec::new();
+        for (root, fields) in service.roots() {
+            roots.push(EncodeSettings::from_fields(root, &fields)?);
+        }
+        Ok(ReencodeConfig {
+            interval: global.seconds("interval")?.unwrap_or(DEFAULT_INTERVAL),
+            order: global
+                .parse("order", |v| {
+                    v.as_str()
+                        .ok_or_else(|| "must be a string".to_string())
+                        .and_then(QueueOrder::from_name)
+                })?
+                .unwrap_or(QueueOrder::Savings),
+            schedule: Schedule::from_fields(global)?,
+            roots,
+        })
+    }
+}
+
+/// What each root wants its files encoded as, for the queue
+fn targets(settings: &[EncodeSettings]) -> Vec<Target> {
+    settings
+        .iter()
+        .map(|s| Target {
+            root: s.root.clone(),
+            extension: s.target_extension.clone(),
+            codec: s.target_codec.clone(),
+            priority: s.priority,
+            max_height: s.profile.scaling.max_height,
+            max_width: s.profile.scaling.max_width,
+            enabled: s.enabled,
+        })
+        .collect()
+}
+
+/// The next `limit` paths that the reencode module would pick up
+pub(crate) fn pending(
+    store: &mut dyn Store,
+    config: &ReencodeConfig,
+    limit: i64,
+) -> Result<Vec<Candidate>, Box<dyn Error>> {
+    store.candidates(&targets(&config.roots), config.order, limit)
+}
+
+/// The settings of the deepest root `path` is in, which it belongs to
+fn deepest_root<'a>(settings: &'a [EncodeSettings], path: &Path) -> Option<&'a EncodeSettings> {
+    settings
+        .iter()
+        .filter(|s| path.starts_with(&s.root))
+        .max_by_key(|s| s.root.trim_end_matches('/').len())
+}
+
+/// Encode a claimed path with the settings of the root it belongs to
+fn process(
+    store: &mut dyn Store,
+    settings: &[EncodeSettings],
+    schedule: &Schedule,
+    id: i64,
+    source_path_s: &str,
+    original_bytes: i64,
+) {
+    let source_path = Path::new(source_path_s);
+    let root = deepest_root(settings, source_path).expect("claimed a path outside of every root");
+    // The file may have changed since it was scanned; leave it for the next scan if so
+    let unsettled = match source_path.metadata() {
+        Ok(metadata) if metadata.len() as i64 != original_bytes => {
+            Some(format!("size changed to {}", metadata.len()))
+        }
+        _ => root.settle.unsettled(source_path),
+    };
+    if let Some(reason) = unsettled {
+        warn!("Not reencoding {:?} yet: {}", source_path, reason);
+        store.mark_unsettled(source_path_s).unwrap();
+        store.release(id).unwrap();
+        return;
+    }
+    let mut job = Job::start(store, id, source_path_s);
+    if let Err(e) = reencode(
+        store,
+        root,
+        schedule,
+        &mut job,
+        id,
+        source_path_s,
+        original_bytes,
+    ) {
+        let (source_temp_path, temp_path) = scratch_paths(root);
+        let scratch = [source_temp_path.as_path(), &temp_path];
+        give_up(store, &mut job, id, &scratch, &*e);
+    }
+}
+
+/// Record why `reencode` stopped short. During a shutdown that is most likely a copy
+/// that was cut short, so the job is cancelled and its path goes back in the queue.
+fn give_up(store: &mut dyn Store, job: &mut Job, id: i64, scratch: &[&Path], error: &dyn Error) {
+    if shutdown::requested() {
+        if let Err(e) = cancel(store, job, id, scratch, &format!("shut down: {}", error)) {
+            warn!("Failed to cancel job {}: {}", job.id, e);
+        }
+        return;
+    }
+    warn!("Failed to reencode {:?}: {}", job.source_path(), error);
+    job.finish(store, "failed", &error.to_string());
+}
+
+/// Reencode one file right away, whatever its place in the queue
+pub(crate) fn reencode_path(
+    store: &mut dyn Store,
+    config: &ReencodeConfig,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let settings = &config.roots;
+    if !deepest_root(settings, path).is_some_and(|s| s.enabled) {
+        return Err(format!(
+            "{:?} is not inside an active root that allows reencoding",
+            path
+        )
+        .into());
+    }
+    let file = ScannedFile::new(path, store)?;
+    file.store(store)?;
+    let claimed = store
+        .claim_path(&file.record.path)?
+        .ok_or_else(|| format!("{:?} is already being reencoded", path))?;
+    // Asking for a file by hand overrides the encoding windows and busy signals
+    let schedule = config.schedule.unrestricted();
+    process(
+        store,
+        settings,
+        &schedule,
+        claimed.id,
+        &claimed.path,
+        claimed.bytes,
+    );
+    Ok(())
+}
 
 pub struct Reencode {}
 impl crate::module::Module for Reencode {
     fn module_name(&self) -> &str {
         "reencode"
     }
-    fn module_iteration(&self, connection: &mut Client) {
+    fn interval(&self, config: &Config) -> Duration {
+        config.reencode.interval
+    }
+    fn module_iteration(&self, store: &mut dyn Store, config: &Config) {
         info!("Searching for targets to reencode");
-        let mut done = false;
-        let target_extension = self.config_string(connection, "target_extension");
-        let target_codec = self.config_string(connection, "target_codec");
-        while !done {
-            done = true;
-            debug!(
-                "Selecting paths where extension+codec do not match {}+{}",
-                &target_extension, &target_codec
-            );
-            let rows = connection
-                .query(
-                    "\
-                UPDATE paths SET in_progress = true WHERE id = ( \
-                    SELECT id FROM paths \
-                    INNER JOIN video_extensions USING(extension) \
-                    WHERE (extension != $1 or codec != $2) AND NOT in_progress \
-                    LIMIT 1 \
-                )\
-                RETURNING id, path, bytes
-                ",
-                    &[&target_extension, &target_codec],
-                )
-                .unwrap();
-            debug!("Got {:?}", rows);
-            for row in rows.iter() {
-                // always just one unless its zero
-                done = false;
-                let _id: i64 = row.get(0);
-                let source_path_s: String = row.get(1);
-                let source_path = Path::new(&source_path_s);
-                let source_temp_path = Path::new("/tmp/in");
-                let original_bytes: i64 = row.get(2);
-                let target_path = source_path.with_extension(&target_extension);
-                let temp_path = Path::new("/tmp/converting.x").with_extension(&target_extension);
-                info!("Copy {:?} to temp", &source_path);
-                fs::copy(source_path, source_temp_path).unwrap_or_else(|_| {
-                    panic!("failed to copy {:?} to {:?}", source_path, source_temp_path)
-                });
-                info!("Converting {:?}", &source_path);
-                let captured = Exec::cmd("ffmpeg")
-                    .arg("-y")
-                    .arg("-loglevel")
-                    .arg("warning")
-                    .arg("-i")
-                    .arg(
-                        source_temp_path
-                            .to_str()
-                            .expect("Could not convert source_temp_path to a string"),
-                    )
-                    .arg("-c:v")
-                    .arg(&target_codec)
-                    .arg("-c:a")
-                    .arg("aac")
-                    .arg("-hide_banner")
-                    .arg("-nostats")
-                    .arg(&temp_path)
-                    .stdout(Redirection::Pipe)
-                    .stderr(Redirection::Pipe)
-                    .capture()
-                    .unwrap();
-                if captured.success() {
-                    info!("cp {:?} {:?}", &temp_path, &target_path);
-                    fs::copy(&temp_path, &target_path).unwrap();
-                    let new_file = ScannedFile::new(&target_path, connection).unwrap();
-                    info!(
-                        "Bytes {:?} -> {:?} = {:?}",
-                        original_bytes,
-                        new_file.bytes,
-                        new_file.bytes - original_bytes
-                    );
-                    let _store_result = new_file.store(connection);
-                    if source_path != target_path {
-                        info!("rm {:?}", &source_path);
-                        fs::remove_file(source_path)
-                            .unwrap_or_else(|_| panic!("failed to remove file {:?}", source_path));
-                        fs::remove_file(source_temp_path).unwrap_or_else(|_| {
-                            panic!("failed to remove file {:?}", source_temp_path)
-                        });
-                    }
-                } else {
-                    warn!("ffmpeg failed: {}", &captured.stderr_str());
-                }
+        let config = &config.reencode;
+        let settings = &config.roots;
+        let targets = targets(settings);
+        let schedule = &config.schedule;
+        while !shutdown::requested() {
+            if let Some(reason) = schedule.blocked() {
+                info!("Not starting anything: {}", reason);
+                break;
+            }
+            debug!("Selecting paths where extension+codec do not match their root's target");
+            let claimed = store.claim_next(&targets, config.order).unwrap();
+            debug!("Got {:?}", claimed);
+            match claimed {
+                Some(c) => process(store, settings, schedule, c.id, &c.path, c.bytes),
+                None => break,
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{backend_tests, file_record, scratch_dir, MemoryStore, TestStore};
+    use crate::store::FileRecord;
+    use chrono::{Duration as ChronoDuration, Local};
+    use serde_json::json;
+
+    backend_tests!(
+        queue_puts_bumped_files_then_root_priority_then_savings,
+        files_larger_than_the_profile_allows_are_queued,
+        order_setting_sorts_by_age,
+        claimed_files_are_not_handed_out_twice,
+        disabled_roots_are_not_queued,
+        files_follow_the_deepest_root_they_are_in,
+    );
+
+    fn add_roots(store: &mut dyn TestStore, roots: &[&str]) {
+        for root in roots {
+            store.add_root(root, true).unwrap();
+        }
+    }
+
+    fn store_with_roots(roots: &[&str]) -> MemoryStore {
+        let mut store = MemoryStore::new();
+        add_roots(&mut store, roots);
+        store
+    }
+
+    fn pending_paths(store: &mut dyn TestStore) -> Vec<String> {
+        let config = Config::load(store, None).unwrap();
+        pending(store, &config.reencode, 100)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.path)
+            .collect()
+    }
+
+    fn queue_puts_bumped_files_then_root_priority_then_savings(store: &mut dyn TestStore) {
+        add_roots(store, &["/lib/a", "/lib/b"]);
+        store
+            .set_root_config("/lib/b", &json!({"reencode": {"priority": 5}}))
+            .unwrap();
+        store.add_file("/lib/a/small.avi", "h264", 10);
+        store.add_file("/lib/a/big.avi", "h264", 100);
+        store.add_file("/lib/b/tiny.avi", "h264", 1);
+        store.add_file("/lib/a/done.mkv", "hevc", 1000);
+        store.add_file("/lib/a/notes.txt", "h264", 1000);
+        store.add_file("/lib/other/outside.avi", "h264", 1000);
+        let claimed = store.add_file("/lib/a/claimed.avi", "h264", 1000);
+        store.claim_path("/lib/a/claimed.avi").unwrap();
+        store.add_file("/lib/a/growing.avi", "h264", 1000);
+        store.mark_unsettled("/lib/a/growing.avi").unwrap();
+        let kept = store.add_file("/lib/a/kept.avi", "h264", 1000);
+        store.keep_original(kept, "/out/kept.mkv").unwrap();
+        store.set_priority("/lib/a/small.avi", 100).unwrap();
+
+        assert_eq!(
+            pending_paths(store),
+            vec!["/lib/a/small.avi", "/lib/b/tiny.avi", "/lib/a/big.avi"]
+        );
+        store.release(claimed).unwrap();
+        assert!(pending_paths(store).contains(&"/lib/a/claimed.avi".to_string()));
+    }
+
+    fn files_larger_than_the_profile_allows_are_queued(store: &mut dyn TestStore) {
+        add_roots(store, &["/lib/mobile", "/lib/tv"]);
+        store
+            .set_service_config(
+                "reencode",
+                &json!({"profiles": {"mobile": {"max_height": 720, "scaler": "lanczos"}}}),
+            )
+            .unwrap();
+        store
+            .set_root_config("/lib/mobile", &json!({"reencode": {"profile": "mobile"}}))
+            .unwrap();
+        store.add_file("/lib/mobile/1080p.mkv", "hevc", 1);
+        store.add_file("/lib/tv/1080p.mkv", "hevc", 1);
+        store
+            .store_file(&FileRecord {
+                height: Some(480),
+                width: Some(854),
+                ..file_record("/lib/mobile/480p.mkv", "hevc", 1)
+            })
+            .unwrap();
+        assert_eq!(pending_paths(store), vec!["/lib/mobile/1080p.mkv"]);
+
+        store
+            .set_service_config(
+                "reencode",
+                &json!({"profiles": {"mobile": {"max_height": 720, "scaler": "sharp"}}}),
+            )
+            .unwrap();
+        assert!(Config::load(store, None)
+            .unwrap_err()
+            .to_string()
+            .starts_with("reencode.profiles.mobile.scaler"));
+    }
+
+    fn order_setting_sorts_by_age(store: &mut dyn TestStore) {
+        add_roots(store, &["/lib"]);
+        store
+            .store_file(&FileRecord {
+                last_modified: Local::now() - ChronoDuration::days(30),
+                ..file_record("/lib/old.avi", "h264", 1)
+            })
+            .unwrap();
+        store.add_file("/lib/new.avi", "h264", 2);
+        store
+            .set_service_config("reencode", &json!({"order": "oldest"}))
+            .unwrap();
+        assert_eq!(pending_paths(store), vec!["/lib/old.avi", "/lib/new.avi"]);
+        store
+            .set_service_config("reencode", &json!({"order": "newest"}))
+            .unwrap();
+        assert_eq!(pending_paths(store), vec!["/lib/new.avi", "/lib/old.avi"]);
+    }
+
+    fn claimed_files_are_not_handed_out_twice(store: &mut dyn TestStore) {
+        add_roots(store, &["/lib"]);
+        store.add_file("/lib/a.avi", "h264", 2);
+        store.add_file("/lib/b.avi", "h264", 1);
+        let mut other = store.connect();
+        let config = Config::load(store, None).unwrap();
+        let targets = targets(&config.reencode.roots);
+        let first = store.claim_next(&targets, config.reencode.order).unwrap();
+        let second = other.claim_next(&targets, config.reencode.order).unwrap();
+        assert_eq!(first.unwrap().path, "/lib/a.avi");
+        assert_eq!(second.unwrap().path, "/lib/b.avi");
+        assert_eq!(
+            store.claim_next(&targets, config.reencode.order).unwrap(),
+            None
+        );
+        assert_eq!(store.claim_path("/lib/a.avi").unwrap(), None);
+    }
+
+    fn disabled_roots_are_not_queued(store: &mut dyn TestStore) {
+        add_roots(store, &["/lib", "/lib/movies", "/lib/movies/keep"]);
+        for root in ["/lib", "/lib/movies/keep"] {
+            store
+                .set_root_config(root, &json!({"reencode": {"enabled": false}}))
+                .unwrap();
+        }
+        store.add_file("/lib/a.avi", "h264", 2);
+        store.add_file("/lib/movies/b.avi", "h264", 2);
+        store.add_file("/lib/movies/keep/c.avi", "h264", 2);
+        assert_eq!(pending_paths(store), vec!["/lib/movies/b.avi"]);
+        let config = Config::load(store, None).unwrap();
+        let targets = targets(&config.reencode.roots);
+        let claimed = store.claim_next(&targets, config.reencode.order).unwrap();
+        assert_eq!(
+            claimed.map(|c| c.path).as_deref(),
+            Some("/lib/movies/b.avi")
+        );
+        assert!(store
+            .claim_next(&targets, config.reencode.order)
+            .unwrap()
+            .is_none());
+    }
+
+    fn files_follow_the_deepest_root_they_are_in(store: &mut dyn TestStore) {
+        add_roots(store, &["/lib", "/lib/kids"]);
+        store
+            .set_root_config(
+                "/lib/kids",
+                &json!({"reencode": {"target_codec": "h264", "target_extension": "mp4"}}),
+            )
+            .unwrap();
+        store.add_file("/lib/kids/done.mp4", "h264", 3);
+        store.add_file("/lib/kids/todo.avi", "h264", 2);
+        store.add_file("/lib/todo.mp4", "h264", 1);
+        assert_eq!(
+            pending_paths(store),
+            vec!["/lib/kids/todo.avi", "/lib/todo.mp4"]
+        );
+        let config = Config::load(store, None).unwrap();
+        let targets = targets(&config.reencode.roots);
+        let mut claimed = Vec::new();
+        while let Some(c) = store.claim_next(&targets, config.reencode.order).unwrap() {
+            claimed.push(c.path);
+        }
+        assert_eq!(claimed, vec!["/lib/kids/todo.avi", "/lib/todo.mp4"]);
+    }
+
+    #[test]
+    fn files_changed_since_the_scan_go_back_to_scan() {
+        let dir = scratch_dir("reencode-changed");
+        let source = dir.join("a.avi");
+        fs::write(&source, b"ten bytes!").unwrap();
+        let source = source.to_str().unwrap();
+        let mut store = store_with_roots(&[dir.to_str().unwrap()]);
+        store.add_file(source, "h264", 20);
+        let config = Config::load(&mut store, None).unwrap();
+        let claimed = store.claim_path(source).unwrap().unwrap();
+        process(
+            &mut store,
+            &config.reencode.roots,
+            &config.reencode.schedule,
+            claimed.id,
+            &claimed.path,
+            claimed.bytes,
+        );
+        let row = store.row(source).unwrap();
+        assert!(!row.in_progress);
+        assert!(row.settled_at.is_none());
+        assert!(store.data().jobs.is_empty());
+    }
+
+    #[test]
+    fn shutdown_during_a_copy_releases_the_path() {
+        let dir = scratch_dir("reencode-shutdown");
+        let source = dir.join("a.avi");
+        let copied = dir.join("in");
+        fs::write(&source, b"source").unwrap();
+        let source_s = source.to_str().unwrap();
+        let mut store = store_with_roots(&[dir.to_str().unwrap()]);
+        store.add_file(source_s, "h264", 6);
+        let claimed = store.claim_path(source_s).unwrap().unwrap();
+        let mut job = Job::start(&mut store, claimed.id, source_s);
+        shutdown::request_here();
+        let error = copy(&source, &copied).unwrap_err();
+        give_up(&mut store, &mut job, claimed.id, &[&copied], &error);
+        assert!(!store.row(source_s).unwrap().in_progress);
+        assert!(!copied.exists());
+        let job = &store.data().jobs[0];
+        assert_eq!(job.state, "cancelled");
+        assert_eq!(job.error.as_deref(), Some("shut down: shutting down"));
+    }
+
+    #[test]
+    fn replace_prior_encode_only_replaces_its_own_output() {
+        let dir = scratch_dir("reencode-collision");
+        let source = dir.join("a.avi");
+        let target = dir.join("a.mkv");
+        fs::write(&source, b"source").unwrap();
+        fs::write(&target, b"someone else's").unwrap();
+        let mut store = store_with_roots(&[dir.to_str().unwrap()]);
+        store
+            .set_service_config("reencode", &json!({"on_collision": "replace_prior_encode"}))
+            .unwrap();
+        store.add_file(target.to_str().unwrap(), "hevc", 14);
+        let config = Config::load(&mut store, None).unwrap();
+        let settings = &config.reencode.roots[0];
+        let resolve =
+            |store: &mut MemoryStore| resolve_collision(store, settings, &source, target.clone());
+        assert_eq!(resolve(&mut store), None);
+        store
+            .set_encoded_from(target.to_str().unwrap(), source.to_str().unwrap())
+            .unwrap();
+        assert_eq!(resolve(&mut store), Some(target.clone()));
+    }
+}
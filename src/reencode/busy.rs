@@ -0,0 +1,248 @@
+use crate::config::{ConfigError, Fields};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a busy check is trusted before asking again, unless `check_interval` is set
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Something that can say the machine is needed for something more important than
+/// encoding, such as a media server that is streaming
+#[derive(Debug)]
+enum Signal {
+    /// An HTTP endpoint returning JSON. The value at `pointer` counts as busy if it is a
+    /// non-empty array or object, a positive number or `true`. With `require`, only array
+    /// elements that have that key are counted.
+    Http {
+        url: String,
+        pointer: String,
+        require: Option<String>,
+        headers: Vec<(String, String)>,
+    },
+    /// Busy while the file exists
+    LockFile(PathBuf),
+    /// Busy while the 1-minute load average is above this
+    Load(f64),
+}
+
+fn is_busy(value: &Value, require: Option<&str>) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().unwrap_or(0.0) > 0.0,
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => match require {
+            None => !items.is_empty(),
+            Some(key) => items
+                .iter()
+                .any(|item| item.get(key).is_some_and(|v| !v.is_null())),
+        },
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+impl Signal {
+    fn check(&self) -> Result<Option<String>, String> {
+        match self {
+            Signal::Http {
+                url,
+                pointer,
+                require,
+                headers,
+            } => {
+                let mut request = ureq::get(url)
+                    .timeout(Duration::from_secs(10))
+                    .set("Accept", "application/json");
+                for (name, value) in headers {
+                    request = request.set(name, value);
+                }
+                let body = request
+                    .call()
+                    .map_err(|e| e.to_string())?
+                    .into_string()
+                    .map_err(|e| e.to_string())?;
+                let json: Value = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+                let value = json.pointer(pointer).unwrap_or(&Value::Null);
+                Ok(Some(format!("{} reports activity", url))
+                    .filter(|_| is_busy(value, require.as_deref())))
+            }
+            Signal::LockFile(path) => {
+                Ok(Some(format!("{:?} exists", path)).filter(|_| path.exists()))
+            }
+            Signal::Load(max) => {
+                let loadavg = fs::read_to_string("/proc/loadavg").map_err(|e| e.to_string())?;
+                let load: f64 = loadavg
+                    .split_whitespace()
+                    .next()
+                    .and_then(|l| l.parse().ok())
+                    .ok_or_else(|| format!("can't parse /proc/loadavg: {}", loadavg))?;
+                Ok(Some(format!("load average {} is above {}", load, max)).filter(|_| load > *max))
+            }
+        }
+    }
+}
+
+/// The `busy` section of the reencode config
+#[derive(Debug, Default)]
+pub struct Busy {
+    signals: Vec<Signal>,
+    check_interval: Duration,
+    /// When the signals were last asked, and what they said
+    last: Mutex<Option<(Instant, Option<String>)>>,
+}
+
+impl Busy {
+    pub fn from_fields(fields: Option<Fields>) -> Result<Busy, ConfigError> {
+        let fields = match fields {
+            None => return Ok(Busy::default()),
+            Some(fields) => fields,
+        };
+        fields.only(&["http", "lock_file", "max_load", "check_interval"])?;
+        let mut signals = Vec::new();
+        if let Some(http) = fields.object("http")? {
+            http.only(&["url", "pointer", "require", "headers"])?;
+            let headers = match http.object("headers")? {
+                None => Vec::new(),
+                Some(headers) => headers
+                    .keys()
+                    .into_iter()
+                    .map(|name| {
+                        let value = headers.string(name)?.unwrap_or_default();
+                        Ok((name.to_string(), value))
+                    })
+                    .collect::<Result<_, ConfigError>>()?,
+            };
+            signals.push(Signal::Http {
+                url: http
+                    .string("url")?
+                    .ok_or_else(|| http.error("url", "missing"))?,
+                pointer: http.string("pointer")?.unwrap_or_default(),
+                require: http.string("require")?,
+                headers,
+            });
+        }
+        if let Some(lock_file) = fields.string("lock_file")? {
+            signals.push(Signal::LockFile(PathBuf::from(lock_file)));
+        }
+        if let Some(max_load) = fields.float("max_load")? {
+            signals.push(Signal::Load(max_load));
+        }
+        Ok(Busy {
+            signals,
+            check_interval: fields
+                .seconds("check_interval")?
+                .unwrap_or(DEFAULT_CHECK_INTERVAL),
+            last: Mutex::new(None),
+        })
+    }
+
+    /// Why encoding should wait, if it should. Signals are asked at most once per
+    /// `check_interval`; a signal that can't be checked doesn't count as busy.
+    pub fn reason(&self) -> Option<String> {
+        if self.signals.is_empty() {
+            return None;
+        }
+        let mut last = self.last.lock().expect("busy lock poisoned");
+        match &*last {
+            Some((checked, reason)) if checked.elapsed() < self.check_interval => reason.clone(),
+            _ => {
+                let reason = self.signals.iter().find_map(|signal| match signal.check() {
+                    Ok(reason) => reason,
+                    Err(e) => {
+                        warn!("Could not check {:?}: {}", signal, e);
+                        None
+                    }
+                });
+                *last = Some((Instant::now(), reason.clone()));
+                reason
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::scratch_dir;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn busy(config: &Value) -> Busy {
+        Busy::from_fields(Some(
+            Fields::new("busy").layer("test", Some(config)).unwrap(),
+        ))
+        .unwrap()
+    }
+
+    /// A media server that answers one request with `body`
+    fn serve(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/sessions", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn http_counts_sessions_that_have_the_required_key() {
+        let url = serve(r#"{"sessions": [{"user": "a"}, {"user": "b", "stream": {}}]}"#);
+        let streaming = busy(&json!({
+            "http": {"url": url, "pointer": "/sessions", "require": "stream"}
+        }));
+        assert_eq!(
+            streaming.reason(),
+            Some(format!("{} reports activity", url))
+        );
+        let url = serve(r#"{"sessions": [{"user": "a"}]}"#);
+        let idle = busy(&json!({
+            "http": {"url": url, "pointer": "/sessions", "require": "stream"}
+        }));
+        assert_eq!(idle.reason(), None);
+    }
+
+    #[test]
+    fn an_unreachable_server_is_not_busy() {
+        // Nothing listens on a port that was just released
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let unreachable = busy(&json!({"http": {"url": format!("http://127.0.0.1:{}/", port)}}));
+        assert!(unreachable.signals[0].check().is_err());
+        assert_eq!(unreachable.reason(), None);
+    }
+
+    #[test]
+    fn lock_file_is_busy_while_it_exists() {
+        let lock = scratch_dir("busy-lock").join("encoding.lock");
+        let locked = busy(&json!({"lock_file": lock, "check_interval": 0}));
+        assert_eq!(locked.reason(), None);
+        fs::write(&lock, "").unwrap();
+        assert_eq!(locked.reason(), Some(format!("{:?} exists", lock)));
+    }
+
+    #[test]
+    fn load_above_the_maximum_is_busy() {
+        let loaded = busy(&json!({"max_load": -1.0}));
+        assert!(loaded.reason().unwrap().ends_with("is above -1"));
+        assert_eq!(busy(&json!({"max_load": 1e9})).reason(), None);
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use subprocess::unix::PopenExt;
+use subprocess::{Exec, ExitStatus, Redirection};
+
+/// One block of ffmpeg's `-progress` output
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// Seconds of output written so far
+    pub out_time: f64,
+    pub fps: f64,
+    /// Encoding speed as a multiple of realtime
+    pub speed: f64,
+}
+
+impl Progress {
+    fn from_block(block: &HashMap<String, String>) -> Progress {
+        let number = |key: &str| {
+            block
+                .get(key)
+                .and_then(|v| v.trim_end_matches('x').trim().parse::<f64>().ok())
+                .unwrap_or(0.0)
+        };
+        Progress {
+            out_time: number("out_time_us") / 1_000_000.0,
+            fps: number("fps"),
+            speed: number("speed"),
+        }
+    }
+
+    pub fn percent(&self, duration: Option<f64>) -> Option<f64> {
+        duration
+            .filter(|d| *d > 0.0)
+            .map(|d| (self.out_time / d * 100.0).min(100.0))
+    }
+
+    /// Seconds until the encode is expected to finish at the current speed
+    pub fn eta(&self, duration: Option<f64>) -> Option<f64> {
+        duration
+            .filter(|_| self.speed > 0.0)
+            .map(|d| ((d - self.out_time) / self.speed).max(0.0))
+    }
+}
+
+/// The blocks of `-progress` output, each ended by a `progress=continue` or, for the
+/// last one, `progress=end` line
+fn blocks(input: impl BufRead) -> impl Iterator<Item = Progress> {
+    let mut lines = input.lines().map_while(Result::ok);
+    std::iter::from_fn(move || {
+        let mut block = HashMap::new();
+        for line in lines.by_ref() {
+            if let Some((key, value)) = line.split_once('=') {
+                if key == "progress" {
+                    return Some(Progress::from_block(&block));
+                }
+                block.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        None
+    })
+}
+
+/// Something that happened while ffmpeg was running
+pub enum Event<'a> {
+    Progress(&'a Progress),
+    /// Nothing was reported for a while, which is normal while ffmpeg is paused
+    Tick,
+}
+
+/// What ffmpeg should be doing next
+#[derive(Debug, PartialEq)]
+pub enum Control {
+    Run,
+    /// Stop the process with SIGSTOP until told to run again
+    Pause,
+    /// Terminate the process; the output is abandoned
+    Cancel,
+}
+
+pub struct Finished {
+    pub status: ExitStatus,
+    pub stderr: String,
+    /// The encode was stopped by `Control::Cancel`
+    pub cancelled: bool,
+}
+
+/// Run an ffmpeg command, calling `on_event` as it reports progress and at least once a
+/// second otherwise. The command must include `-progress pipe:1` and must not redirect
+/// stdout/stderr itself.
+pub fn run(
+    command: Exec,
+    mut on_event: impl FnMut(Event) -> Control,
+) -> Result<Finished, Box<dyn Error>> {
+    let mut popen = command
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .popen()?;
+    let stdout = popen.stdout.take().expect("ffmpeg stdout was not piped");
+    let mut stderr = popen.stderr.take().expect("ffmpeg stderr was not piped");
+
+    let (sender, receiver) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        for progress in blocks(BufReader::new(stdout)) {
+            if sender.send(progress).is_err() {
+                break;
+            }
+        }
+    });
+    // Drained on its own thread so a chatty ffmpeg can't block on a full pipe
+    let errors = thread::spawn(move || {
+        let mut buffer = String::new();
+        let _ = stderr.read_to_string(&mut buffer);
+        buffer
+    });
+
+    let mut stopped = false;
+    let mut cancelled = false;
+    loop {
+        let control = match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(progress) => on_event(Event::Progress(&progress)),
+            Err(RecvTimeoutError::Timeout) => on_event(Event::Tick),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if cancelled {
+            continue;
+        }
+        if control == Control::Cancel {
+            popen.send_signal(libc::SIGTERM)?;
+            if stopped {
+                popen.send_signal(libc::SIGCONT)?;
+            }
+            cancelled = true;
+        } else if control == Control::Pause && !stopped {
+            popen.send_signal(libc::SIGSTOP)?;
+            stopped = true;
+        } else if control == Control::Run && stopped {
+            popen.send_signal(libc::SIGCONT)?;
+            stopped = false;
+        }
+    }
+    let status = popen.wait()?;
+    let _ = reader.join();
+    let stderr = errors.join().unwrap_or_default();
+    Ok(Finished {
+        status,
+        stderr,
+        cancelled,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Captured from `ffmpeg -progress pipe:1` encoding a 60 second file
+    const OUTPUT: &str = "\
+frame=0
+fps=0.00
+stream_0_0_q=0.0
+bitrate=N/A
+total_size=44
+out_time_us=N/A
+out_time_ms=N/A
+out_time=N/A
+dup_frames=0
+drop_frames=0
+speed=N/A
+progress=continue
+frame=360
+fps=71.87
+stream_0_0_q=28.0
+bitrate=1402.3kbits/s
+total_size=2637824
+out_time_us=15048000
+out_time_ms=15048000
+out_time=00:00:15.048000
+dup_frames=0
+drop_frames=0
+speed=3.01x
+progress=continue
+frame=1440
+fps=72.10
+stream_0_0_q=-1.0
+bitrate=1387.9kbits/s
+total_size=10409216
+out_time_us=60000000
+out_time_ms=60000000
+out_time=00:01:00.000000
+dup_frames=0
+drop_frames=0
+speed=3.02x
+progress=end
+";
+
+    #[test]
+    fn reads_each_block_through_the_end() {
+        let progress: Vec<Progress> = blocks(OUTPUT.as_bytes()).collect();
+        assert_eq!(progress.len(), 3);
+
+        let running = &progress[1];
+        assert_eq!(running.out_time, 15.048);
+        assert_eq!(running.fps, 71.87);
+        assert_eq!(running.speed, 3.01);
+        assert_eq!(running.percent(Some(60.0)), Some(15.048 / 60.0 * 100.0));
+        assert_eq!(running.eta(Some(60.0)), Some((60.0 - 15.048) / 3.01));
+
+        let end = &progress[2];
+        assert_eq!(end.percent(Some(60.0)), Some(100.0));
+        assert_eq!(end.eta(Some(60.0)), Some(0.0));
+    }
+
+    #[test]
+    fn missing_values_count_as_nothing_done_yet() {
+        let starting = &blocks(OUTPUT.as_bytes()).next().unwrap();
+        assert_eq!(starting.out_time, 0.0);
+        assert_eq!(starting.percent(Some(60.0)), Some(0.0));
+        // No speed yet, so no estimate
+        assert_eq!(starting.eta(Some(60.0)), None);
+
+        let empty = Progress::from_block(&HashMap::new());
+        assert_eq!(empty.out_time, 0.0);
+        assert_eq!(empty.speed, 0.0);
+    }
+
+    #[test]
+    fn no_duration_means_no_percent_or_eta() {
+        let running = Progress {
+            out_time: 10.0,
+            fps: 24.0,
+            speed: 2.0,
+        };
+        assert_eq!(running.percent(None), None);
+        assert_eq!(running.percent(Some(0.0)), None);
+        assert_eq!(running.eta(None), None);
+        // Past the probed duration, as happens with a short audio track
+        assert_eq!(running.percent(Some(5.0)), Some(100.0));
+        assert_eq!(running.eta(Some(5.0)), Some(0.0));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::scan::ffprobe::{MasteringDisplay, ProbedInfo};
+
+/// Encoders that take HDR10's static metadata through `-x265-params`
+const X265_CODECS: [&str; 3] = ["hevc", "h265", "libx265"];
+
+/// Why the source's picture can't survive an encode to another codec, if it can't
+pub fn unsupported(info: &ProbedInfo) -> Option<String> {
+    match &info.dolby_vision {
+        // Without the Dolby Vision layer, what's left has the wrong colours
+        Some(dolby_vision) if dolby_vision.compatibility_id == 0 => Some(format!(
+            "Dolby Vision profile {} has no HDR10, HLG or SDR base layer to encode",
+            dolby_vision.profile
+        )),
+        _ => None,
+    }
+}
+
+/// ffmpeg output options that keep the source's bit depth, colour properties and HDR10
+/// metadata. A Dolby Vision source keeps only its base layer.
+pub fn args(info: &ProbedInfo, codec: &str) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    if info.bit_depth.is_some_and(|depth| depth >= 10) {
+        let pix_fmt = match info.pix_fmt.as_deref() {
+            Some(pix_fmt) if pix_fmt.ends_with("p10le") => pix_fmt,
+            _ => "yuv420p10le",
+        };
+        args.extend(["-pix_fmt".to_string(), pix_fmt.to_string()]);
+    }
+    for (option, value) in [
+        ("-color_primaries", &info.color_primaries),
+        ("-color_trc", &info.color_transfer),
+        ("-colorspace", &info.color_space),
+    ] {
+        if let Some(value) = value {
+            args.extend([option.to_string(), value.clone()]);
+        }
+    }
+    if info.mastering_display.is_none() && info.content_light.is_none() {
+        return args;
+    }
+    if !X265_CODECS.contains(&codec) {
+        warn!(
+            "Can't pass HDR10 mastering display and light levels to {}; they will be lost",
+            codec
+        );
+        return args;
+    }
+    let mut params = vec!["hdr10=1".to_string(), "repeat-headers=1".to_string()];
+    if let Some(display) = &info.mastering_display {
+        params.push(format!("master-display={}", master_display(display)));
+    }
+    if let Some(light) = &info.content_light {
+        params.push(format!(
+            "max-cll={},{}",
+            light.max_content, light.max_average
+        ));
+    }
+    args.extend(["-x265-params".to_string(), params.join(":")]);
+    args
+}
+
+/// x265's notation: chromaticities in units of 0.00002 and luminance of 0.0001 cd/m²
+fn master_display(display: &MasteringDisplay) -> String {
+    let point = |(x, y): (f64, f64)| format!("({},{})", chromaticity(x), chromaticity(y));
+    format!(
+        "G{}B{}R{}WP{}L({},{})",
+        point(display.green),
+        point(display.blue),
+        point(display.red),
+        point(display.white_point),
+        (display.max_luminance * 10000.0).round(),
+        (display.min_luminance * 10000.0).round()
+    )
+}
+
+fn chromaticity(value: f64) -> f64 {
+    (value * 50000.0).round()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::ffprobe::{ContentLight, DolbyVision};
+
+    fn hdr10() -> ProbedInfo {
+        ProbedInfo {
+            pix_fmt: Some("yuv420p10le".to_string()),
+            bit_depth: Some(10),
+            color_primaries: Some("bt2020".to_string()),
+            color_transfer: Some("smpte2084".to_string()),
+            color_space: Some("bt2020nc".to_string()),
+            mastering_display: Some(MasteringDisplay {
+                red: (0.68, 0.32),
+                green: (0.265, 0.69),
+                blue: (0.15, 0.06),
+                white_point: (0.3127, 0.329),
+                min_luminance: 0.005,
+                max_luminance: 1000.0,
+            }),
+            content_light: Some(ContentLight {
+                max_content: 1000,
+                max_average: 400,
+            }),
+            ..ProbedInfo::default()
+        }
+    }
+
+    #[test]
+    fn hdr10_keeps_its_depth_and_metadata() {
+        assert_eq!(
+            args(&hdr10(), "hevc").join(" "),
+            "-pix_fmt yuv420p10le -color_primaries bt2020 -color_trc smpte2084 \
+                -colorspace bt2020nc -x265-params hdr10=1:repeat-headers=1:\
+                master-display=G(13250,34500)B(7500,3000)R(34000,16000)WP(15635,16450)\
+                L(10000000,50):max-cll=1000,400"
+        );
+        let sdr = ProbedInfo {
+            bit_depth: Some(8),
+            ..ProbedInfo::default()
+        };
+        assert!(args(&sdr, "hevc").is_empty());
+    }
+
+    #[test]
+    fn dolby_vision_needs_a_base_layer() {
+        let mut info = hdr10();
+        info.dolby_vision = Some(DolbyVision {
+            profile: 8,
+            compatibility_id: 1,
+        });
+        assert_eq!(unsupported(&info), None);
+        info.dolby_vision = Some(DolbyVision {
+            profile: 5,
+            compatibility_id: 0,
+        });
+        assert!(unsupported(&info).is_some());
+    }
+}
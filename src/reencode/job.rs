@@ -0,0 +1,150 @@
+use crate::metrics;
+use crate::reencode::ffmpeg::Progress;
+use crate::scan::ffprobe::ProbedInfo;
+use crate::store::{JobRecord, Store};
+use chrono::Local;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use subprocess::ExitStatus;
+
+/// How often a running encode writes its progress to `reencode_jobs`
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// How much of the end of ffmpeg's stderr is kept with a job
+const STDERR_EXCERPT_LENGTH: usize = 4096;
+
+/// A row in `reencode_jobs` recording one attempt to reencode a file
+pub struct Job {
+    pub id: i64,
+    record: JobRecord,
+    last_update: Option<Instant>,
+}
+
+impl Job {
+    pub fn start(store: &mut dyn Store, path_id: i64, source_path: &str) -> Job {
+        let now = Local::now();
+        let mut record = JobRecord {
+            path_id: Some(path_id),
+            source_path: source_path.to_string(),
+            state: "running".to_string(),
+            started_at: now,
+            updated_at: now,
+            ..Default::default()
+        };
+        record.id = store.insert_job(&record).unwrap();
+        Job {
+            id: record.id,
+            record,
+            last_update: None,
+        }
+    }
+
+    pub fn source_path(&self) -> &str {
+        &self.record.source_path
+    }
+
+    fn save(&mut self, store: &mut dyn Store) {
+        self.record.updated_at = Local::now();
+        store.update_job(&self.record).unwrap();
+    }
+
+    pub fn source(&mut self, store: &mut dyn Store, info: &ProbedInfo, bytes: i64) {
+        self.record.duration = info.duration;
+        self.record.source_probe = Some(info.summary());
+        self.record.source_bytes = Some(bytes);
+        self.save(store);
+    }
+
+    pub fn command(&mut self, store: &mut dyn Store, command: &str, profile: &str, output: &Path) {
+        self.record.command = Some(command.to_string());
+        self.record.profile = Some(profile.to_string());
+        self.record.output_path = Some(format!("{}", output.display()));
+        self.save(store);
+    }
+
+    pub fn progress(&mut self, store: &mut dyn Store, progress: &Progress) {
+        let percent = progress.percent(self.record.duration);
+        let eta = progress.eta(self.record.duration);
+        metrics::ENCODE_PERCENT.set(percent.unwrap_or(0.0));
+        metrics::ENCODE_FPS.set(progress.fps);
+        metrics::ENCODE_SPEED.set(progress.speed);
+        metrics::ENCODE_ETA.set(eta.unwrap_or(0.0));
+        if self
+            .last_update
+            .is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_update = Some(Instant::now());
+        trace!("Job {} progress {:?}", self.id, progress);
+        self.record.percent = percent;
+        self.record.fps = Some(progress.fps);
+        self.record.speed = Some(progress.speed);
+        self.record.eta_seconds = eta;
+        self.save(store);
+    }
+
+    /// Keep the job from looking abandoned while ffmpeg is paused or works on something
+    /// other than the encode
+    pub fn touch(&mut self, store: &mut dyn Store) {
+        if self
+            .last_update
+            .is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_update = Some(Instant::now());
+        self.save(store);
+    }
+
+    /// Mark the job as `running` or `paused`
+    pub fn state(&mut self, store: &mut dyn Store, state: &str) {
+        self.record.state = state.to_string();
+        self.save(store);
+    }
+
+    pub fn exit_status(&mut self, status: &ExitStatus) {
+        self.record.exit_status = Some(format!("{:?}", status));
+    }
+
+    pub fn stderr(&mut self, stderr: &str) {
+        let mut start = stderr.len().saturating_sub(STDERR_EXCERPT_LENGTH);
+        while !stderr.is_char_boundary(start) {
+            start += 1;
+        }
+        self.record.stderr = Some(stderr[start..].to_string());
+    }
+
+    pub fn output(&mut self, info: &ProbedInfo, bytes: i64) {
+        self.record.output_probe = Some(info.summary());
+        self.record.output_bytes = Some(bytes);
+    }
+
+    /// The lowest `metric` score of the output's samples, from the latest attempt
+    pub fn quality(&mut self, store: &mut dyn Store, metric: &str, score: f64) {
+        self.record.quality_metric = Some(metric.to_string());
+        self.record.quality_score = Some(score);
+        self.save(store);
+    }
+
+    /// Record the outcome: `state` is one of succeeded, failed, skipped or cancelled, and
+    /// `error` explains anything other than success
+    pub fn finish(&mut self, store: &mut dyn Store, state: &str, error: &str) {
+        metrics::ENCODE_PERCENT.set(0.0);
+        metrics::ENCODE_FPS.set(0.0);
+        metrics::ENCODE_SPEED.set(0.0);
+        metrics::ENCODE_ETA.set(0.0);
+        let record = &mut self.record;
+        record.state = state.to_string();
+        record.finished_at = Some(Local::now());
+        record.eta_seconds = None;
+        if state == "succeeded" {
+            record.percent = Some(100.0);
+        }
+        record.error = Some(error.to_string()).filter(|e| !e.is_empty());
+        record.bytes_saved = record
+            .source_bytes
+            .zip(record.output_bytes)
+            .map(|(source, output)| source - output);
+        self.save(store);
+    }
+}
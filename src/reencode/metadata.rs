@@ -0,0 +1,92 @@
+use crate::config::{ConfigError, Fields};
+use filetime::FileTime;
+use std::fs;
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::Path;
+
+/// Which attributes of the original are copied onto its encoded replacement
+#[derive(Debug)]
+pub struct Preserve {
+    times: bool,
+    owner: bool,
+    mode: bool,
+    xattrs: bool,
+}
+
+impl Preserve {
+    pub fn from_fields(fields: Option<Fields>) -> Result<Preserve, ConfigError> {
+        let fields = fields.unwrap_or_else(|| Fields::new("preserve"));
+        fields.only(&["times", "owner", "mode", "xattrs"])?;
+        let flag = |key: &str| fields.bool(key).map(|v| v.unwrap_or(true));
+        Ok(Preserve {
+            times: flag("times")?,
+            owner: flag("owner")?,
+            mode: flag("mode")?,
+            xattrs: flag("xattrs")?,
+        })
+    }
+}
+
+/// Copy ownership, permissions, extended attributes and timestamps from `source` to
+/// `target`. Failures are logged rather than returned: a missing attribute shouldn't
+/// throw away a finished encode.
+pub fn copy_metadata(source: &Path, target: &Path, preserve: &Preserve) {
+    let metadata = match fs::metadata(source) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Can't read metadata of {:?}: {}", source, e);
+            return;
+        }
+    };
+    // chown may clear setuid/setgid bits, so it has to come before the mode
+    if preserve.owner {
+        if let Err(e) = chown(target, Some(metadata.uid()), Some(metadata.gid())) {
+            warn!(
+                "Can't set owner of {:?} to {}:{}: {}",
+                target,
+                metadata.uid(),
+                metadata.gid(),
+                e
+            );
+        }
+    }
+    if preserve.mode {
+        if let Err(e) = fs::set_permissions(target, metadata.permissions()) {
+            warn!("Can't set mode of {:?}: {}", target, e);
+        }
+    }
+    if preserve.xattrs {
+        copy_xattrs(source, target);
+    }
+    if preserve.times {
+        let atime = FileTime::from_last_access_time(&metadata);
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        if let Err(e) = filetime::set_file_times(target, atime, mtime) {
+            warn!("Can't set timestamps of {:?}: {}", target, e);
+        }
+    }
+}
+
+fn copy_xattrs(source: &Path, target: &Path) {
+    if !xattr::SUPPORTED_PLATFORM {
+        return;
+    }
+    let names = match xattr::list(source) {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("Can't list extended attributes of {:?}: {}", source, e);
+            return;
+        }
+    };
+    for name in names {
+        match xattr::get(source, &name) {
+            Ok(Some(value)) => {
+                if let Err(e) = xattr::set(target, &name, &value) {
+                    warn!("Can't set attribute {:?} on {:?}: {}", &name, target, e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Can't read attribute {:?} of {:?}: {}", &name, source, e),
+        }
+    }
+}
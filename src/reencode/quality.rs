@@ -0,0 +1,290 @@
+use super::ffmpeg::Finished;
+use super::schedule::Schedule;
+use crate::config::{ConfigError, Fields};
+use crate::scan::ffprobe::ProbedInfo;
+use crate::scan::health::sample_windows;
+use regex::Regex;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use subprocess::Exec;
+
+/// The profile keys read by `QualityCheck::from_fields`
+pub const KEYS: [&str; 6] = [
+    "quality_metric",
+    "min_quality",
+    "quality_samples",
+    "quality_sample_seconds",
+    "quality_retries",
+    "quality_crf_step",
+];
+
+const DEFAULT_SAMPLES: u64 = 3;
+const DEFAULT_SAMPLE_SECONDS: u64 = 10;
+const DEFAULT_CRF_STEP: i64 = 3;
+
+/// How an encode is compared with its source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    /// 0 to 100; 93 or more is usually indistinguishable
+    Vmaf,
+    /// 0 to 1
+    Ssim,
+}
+
+impl Metric {
+    fn from_name(name: &str) -> Result<Metric, String> {
+        match name {
+            "vmaf" => Ok(Metric::Vmaf),
+            "ssim" => Ok(Metric::Ssim),
+            _ => Err("must be vmaf or ssim".to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Vmaf => "vmaf",
+            Metric::Ssim => "ssim",
+        }
+    }
+
+    fn filter(&self) -> &'static str {
+        match self {
+            Metric::Vmaf => "libvmaf",
+            Metric::Ssim => "ssim",
+        }
+    }
+
+    /// The score in what ffmpeg prints when the filter is done
+    fn score(&self, stderr: &str) -> Option<f64> {
+        lazy_static! {
+            static ref VMAF: Regex = Regex::new(r"VMAF score: ([\d.]+)").unwrap();
+            static ref SSIM: Regex = Regex::new(r"SSIM .*All:([\d.]+)").unwrap();
+        }
+        let pattern = match self {
+            Metric::Vmaf => &*VMAF,
+            Metric::Ssim => &*SSIM,
+        };
+        let captures = pattern.captures_iter(stderr).last()?;
+        f64::from_str(&captures[1]).ok()
+    }
+}
+
+/// Comparing samples of an encode with its source, and what to do when it falls short
+#[derive(Debug)]
+pub struct QualityCheck {
+    pub metric: Metric,
+    pub min_quality: f64,
+    samples: u64,
+    sample_seconds: u64,
+    /// How many more encodes to try, each with a lower crf, before giving up
+    pub retries: u64,
+    pub crf_step: i64,
+}
+
+impl QualityCheck {
+    /// `None` unless `quality_metric` is set. Retries lower the crf, so they need one.
+    pub fn from_fields(
+        fields: &Fields,
+        crf: Option<i64>,
+    ) -> Result<Option<QualityCheck>, ConfigError> {
+        let metric = match fields.parse("quality_metric", |v| {
+            v.as_str()
+                .ok_or_else(|| "must be a string".to_string())
+                .and_then(Metric::from_name)
+        })? {
+            Some(metric) => metric,
+            None => {
+                return match KEYS[1..].iter().find(|key| fields.get(key).is_some()) {
+                    Some(key) => Err(fields.error(key, "needs quality_metric")),
+                    None => Ok(None),
+                }
+            }
+        };
+        let min_quality = fields
+            .float("min_quality")?
+            .ok_or_else(|| fields.error("min_quality", "must be set with quality_metric"))?;
+        let retries = fields.count("quality_retries")?.unwrap_or(0);
+        if retries > 0 && crf.is_none() {
+            return Err(fields.error("quality_retries", "needs crf to raise quality with"));
+        }
+        let positive = |key: &str, default: u64| -> Result<u64, ConfigError> {
+            match fields.count(key)?.unwrap_or(default) {
+                0 => Err(fields.error(key, "must be at least 1")),
+                n => Ok(n),
+            }
+        };
+        Ok(Some(QualityCheck {
+            metric,
+            min_quality,
+            samples: positive("quality_samples", DEFAULT_SAMPLES)?,
+            sample_seconds: positive("quality_sample_seconds", DEFAULT_SAMPLE_SECONDS)?,
+            retries,
+            crf_step: positive("quality_crf_step", DEFAULT_CRF_STEP as u64)? as i64,
+        }))
+    }
+
+    /// The lowest score of the samples of `output`, compared with `source` after scaling
+    /// it back to the source's size and matching their frame rates. Each sample's ffmpeg
+    /// command goes to `run`; `None` if one of them was cancelled.
+    pub fn measure(
+        &self,
+        schedule: &Schedule,
+        source: (&Path, &ProbedInfo),
+        output: (&Path, &ProbedInfo),
+        mut run: impl FnMut(Exec) -> Result<Finished, Box<dyn Error>>,
+    ) -> Result<Option<f64>, Box<dyn Error>> {
+        let windows = match source.1.duration {
+            Some(duration) => sample_windows(duration, self.samples, self.sample_seconds),
+            None => vec![None],
+        };
+        let mut lowest: Option<f64> = None;
+        for window in windows {
+            let finished = run(self.command(schedule, source, output, window))?;
+            if finished.cancelled {
+                return Ok(None);
+            }
+            let score = match self.metric.score(&finished.stderr) {
+                Some(score) if finished.status.success() => score,
+                _ => {
+                    return Err(format!(
+                        "no {} score from ffmpeg: {}",
+                        self.metric.name(),
+                        finished.stderr.lines().last().unwrap_or("")
+                    )
+                    .into())
+                }
+            };
+            lowest = Some(lowest.map_or(score, |l| l.min(score)));
+        }
+        Ok(lowest)
+    }
+
+    /// Compares one `window` of the two files, or all of them
+    fn command(
+        &self,
+        schedule: &Schedule,
+        source: (&Path, &ProbedInfo),
+        output: (&Path, &ProbedInfo),
+        window: Option<(f64, u64)>,
+    ) -> Exec {
+        let mut command = schedule
+            .command("ffmpeg")
+            .arg("-nostdin")
+            .arg("-hide_banner")
+            .arg("-nostats");
+        // The encoded output first: the filters take the distorted video, then the reference
+        for input in [output.0, source.0] {
+            if let Some((start, seconds)) = window {
+                command = command
+                    .arg("-ss")
+                    .arg(format!("{:.3}", start))
+                    .arg("-t")
+                    .arg(seconds.to_string());
+            }
+            command = command.arg("-i").arg(input);
+        }
+        command
+            .arg("-lavfi")
+            .arg(self.graph(source.1, output.1))
+            .arg("-progress")
+            .arg("pipe:1")
+            .arg("-f")
+            .arg("null")
+            .arg("-")
+    }
+
+    /// The filter graph comparing input 0, the output, with input 1, the source
+    fn graph(&self, source_info: &ProbedInfo, output_info: &ProbedInfo) -> String {
+        let mut distorted = vec!["setpts=PTS-STARTPTS".to_string()];
+        if let (Some(width), Some(height)) = (source_info.width, source_info.height) {
+            if (output_info.width, output_info.height) != (Some(width), Some(height)) {
+                distorted.push(format!("scale={}:{}:flags=bicubic", width, height));
+            }
+        }
+        let mut reference = vec!["setpts=PTS-STARTPTS".to_string()];
+        if let Some(rate) = output_info.frame_rate {
+            if source_info
+                .frame_rate
+                .is_some_and(|r| (r - rate).abs() > 0.001)
+            {
+                reference.push(format!("fps={}", rate));
+            }
+        }
+        format!(
+            "[0:v]{}[distorted];[1:v]{}[reference];[distorted][reference]{}",
+            distorted.join(","),
+            reference.join(","),
+            self.metric.filter()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_scores_from_ffmpeg() {
+        let vmaf = "[Parsed_libvmaf_4 @ 0x5581] VMAF score: 94.731266\n";
+        assert_eq!(Metric::Vmaf.score(vmaf), Some(94.731266));
+        let ssim = "[Parsed_ssim_4 @ 0x55] SSIM Y:0.991 (20.4) U:0.995 (23.0) V:0.994 (22.4) \
+            All:0.992457 (21.2)\n";
+        assert_eq!(Metric::Ssim.score(ssim), Some(0.992457));
+        assert_eq!(
+            Metric::Vmaf.score("Error initializing filter 'libvmaf'"),
+            None
+        );
+    }
+
+    #[test]
+    fn retries_need_a_crf_to_lower() {
+        let config = serde_json::json!({"quality_metric": "ssim", "min_quality": 0.98,
+            "quality_retries": 2});
+        let fields = Fields::new("default")
+            .layer("config", Some(&config))
+            .unwrap();
+        assert!(QualityCheck::from_fields(&fields, None).is_err());
+        let check = QualityCheck::from_fields(&fields, Some(28))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (check.metric, check.retries, check.crf_step),
+            (Metric::Ssim, 2, 3)
+        );
+        let config = serde_json::json!({"min_quality": 93});
+        let fields = Fields::new("default")
+            .layer("config", Some(&config))
+            .unwrap();
+        assert!(QualityCheck::from_fields(&fields, Some(28)).is_err());
+    }
+
+    #[test]
+    fn compares_at_the_source_size_and_output_rate() {
+        let check = QualityCheck {
+            metric: Metric::Vmaf,
+            min_quality: 93.0,
+            samples: 3,
+            sample_seconds: 10,
+            retries: 0,
+            crf_step: 3,
+        };
+        let source = ProbedInfo {
+            width: Some(3840),
+            height: Some(2160),
+            frame_rate: Some(59.94),
+            ..ProbedInfo::default()
+        };
+        let output = ProbedInfo {
+            width: Some(1920),
+            height: Some(1080),
+            frame_rate: Some(30.0),
+            ..ProbedInfo::default()
+        };
+        assert_eq!(
+            check.graph(&source, &output),
+            "[0:v]setpts=PTS-STARTPTS,scale=3840:2160:flags=bicubic[distorted];\
+                [1:v]setpts=PTS-STARTPTS,fps=30[reference];[distorted][reference]libvmaf"
+        );
+    }
+}
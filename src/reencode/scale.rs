@@ -0,0 +1,186 @@
+use crate::config::{ConfigError, Fields};
+use crate::scan::ffprobe;
+
+/// The profile keys read by `Scaling::from_fields`
+pub const KEYS: [&str; 5] = [
+    "max_height",
+    "max_width",
+    "scaler",
+    "frame_rate",
+    "max_frame_rate",
+];
+
+/// The algorithms ffmpeg's scale filter accepts as `flags`
+const SCALERS: [&str; 10] = [
+    "fast_bilinear",
+    "bilinear",
+    "bicubic",
+    "neighbor",
+    "area",
+    "bicublin",
+    "gauss",
+    "sinc",
+    "lanczos",
+    "spline",
+];
+
+/// A frame rate as configured, e.g. 25 or "24000/1001"
+#[derive(Debug)]
+struct FrameRate {
+    text: String,
+    value: f64,
+}
+
+impl FrameRate {
+    fn from_fields(fields: &Fields, key: &str) -> Result<Option<FrameRate>, ConfigError> {
+        fields.parse(key, |v| {
+            ffprobe::rational(v)
+                .filter(|r| r.is_finite() && *r > 0.0)
+                .map(|value| FrameRate {
+                    text: v.as_str().map_or_else(|| v.to_string(), str::to_string),
+                    value,
+                })
+                .ok_or_else(|| {
+                    "must be a positive number or a fraction like 24000/1001".to_string()
+                })
+        })
+    }
+}
+
+/// How a profile changes the size and frame rate of what it encodes
+#[derive(Debug, Default)]
+pub struct Scaling {
+    pub max_height: Option<i32>,
+    pub max_width: Option<i32>,
+    scaler: Option<String>,
+    /// Every encode gets this rate
+    frame_rate: Option<FrameRate>,
+    /// Only faster sources are slowed to this rate
+    max_frame_rate: Option<FrameRate>,
+}
+
+impl Scaling {
+    pub fn from_fields(fields: &Fields) -> Result<Scaling, ConfigError> {
+        let dimension = |key: &str| -> Result<Option<i32>, ConfigError> {
+            fields.parse(key, |v| {
+                v.as_u64()
+                    .and_then(|d| i32::try_from(d).ok())
+                    .filter(|d| *d >= 2)
+                    .ok_or_else(|| "must be a whole number of pixels, at least 2".to_string())
+            })
+        };
+        let scaler = fields.string("scaler")?;
+        if let Some(scaler) = &scaler {
+            if !SCALERS.contains(&scaler.as_str()) {
+                return Err(
+                    fields.error("scaler", format!("must be one of {}", SCALERS.join(", ")))
+                );
+            }
+        }
+        let frame_rate = FrameRate::from_fields(fields, "frame_rate")?;
+        let max_frame_rate = FrameRate::from_fields(fields, "max_frame_rate")?;
+        if frame_rate.is_some() && max_frame_rate.is_some() {
+            return Err(fields.error("max_frame_rate", "can't be set along with frame_rate"));
+        }
+        Ok(Scaling {
+            max_height: dimension("max_height")?,
+            max_width: dimension("max_width")?,
+            scaler,
+            frame_rate,
+            max_frame_rate,
+        })
+    }
+
+    /// The `-vf` filters for a source `width` by `height` pixels at `fps`, if it needs any
+    pub fn filters(
+        &self,
+        width: Option<i32>,
+        height: Option<i32>,
+        fps: Option<f64>,
+    ) -> Option<String> {
+        let mut filters = Vec::new();
+        if let Some((width, height)) = self.size(width, height) {
+            let mut scale = format!("scale={}:{}", width, height);
+            if let Some(scaler) = &self.scaler {
+                scale.push_str(&format!(":flags={}", scaler));
+            }
+            filters.push(scale);
+        }
+        let rate = match (&self.frame_rate, &self.max_frame_rate, fps) {
+            (Some(rate), _, _) => Some(rate),
+            (None, Some(max), Some(fps)) if fps > max.value + 0.001 => Some(max),
+            _ => None,
+        };
+        if let Some(rate) = rate {
+            filters.push(format!("fps={}", rate.text));
+        }
+        (!filters.is_empty()).then(|| filters.join(","))
+    }
+
+    /// The largest size within the caps with the source's aspect ratio, if the source
+    /// exceeds them. Both sides stay even, as 4:2:0 chroma needs.
+    fn size(&self, width: Option<i32>, height: Option<i32>) -> Option<(i32, i32)> {
+        let (width, height) = (width? as f64, height? as f64);
+        let factor = [
+            self.max_width.map(|max| max as f64 / width),
+            self.max_height.map(|max| max as f64 / height),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(1.0, f64::min);
+        if factor >= 1.0 {
+            return None;
+        }
+        let even = |side: f64| ((side * factor / 2.0).round() as i32 * 2).max(2);
+        Some((even(width), even(height)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaling(max_height: Option<i32>, max_width: Option<i32>) -> Scaling {
+        Scaling {
+            max_height,
+            max_width,
+            ..Scaling::default()
+        }
+    }
+
+    #[test]
+    fn downscales_only_what_exceeds_the_caps() {
+        let p1080 = scaling(Some(1080), None);
+        assert_eq!(
+            p1080.filters(Some(3840), Some(2160), None).as_deref(),
+            Some("scale=1920:1080")
+        );
+        // Scope films are limited by their width
+        let fit = scaling(Some(1080), Some(1920));
+        assert_eq!(
+            fit.filters(Some(4096), Some(1716), None).as_deref(),
+            Some("scale=1920:804")
+        );
+        assert_eq!(p1080.filters(Some(1280), Some(720), None), None);
+        assert_eq!(p1080.filters(None, None, None), None);
+    }
+
+    #[test]
+    fn caps_the_frame_rate() {
+        let capped = Scaling {
+            max_frame_rate: Some(FrameRate {
+                text: "30".to_string(),
+                value: 30.0,
+            }),
+            scaler: Some("lanczos".to_string()),
+            ..scaling(Some(720), None)
+        };
+        assert_eq!(
+            capped
+                .filters(Some(1920), Some(1080), Some(59.94))
+                .as_deref(),
+            Some("scale=1280:720:flags=lanczos,fps=30")
+        );
+        assert_eq!(capped.filters(Some(1280), Some(720), Some(23.976)), None);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::config::{ConfigError, Fields};
+use crate::reencode::busy::Busy;
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Timelike, Weekday};
+use subprocess::Exec;
+
+/// A span of the day during which encoding is allowed, on some days of the week. Written
+/// as `22:00-06:00`, `Mon-Fri 01:00-07:00` or `Sat,Sun 00:00-24:00`. A span that ends
+/// before it starts runs past midnight into the next day.
+#[derive(Debug)]
+pub struct Window {
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+/// Minutes since midnight of `HH:MM`, allowing `24:00` for the end of the day
+fn parse_time(s: &str) -> Result<u32, String> {
+    if s == "24:00" {
+        return Ok(24 * 60);
+    }
+    let time = NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| format!("bad time {}", s))?;
+    Ok(time.hour() * 60 + time.minute())
+}
+
+fn parse_day(s: &str) -> Result<Weekday, String> {
+    s.parse::<Weekday>().map_err(|_| format!("bad day {}", s))
+}
+
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    for part in s.split(',').map(str::trim) {
+        match part.split_once('-') {
+            None => days[parse_day(part)?.num_days_from_monday() as usize] = true,
+            Some((first, last)) => {
+                let mut day = parse_day(first)?;
+                let last = parse_day(last)?;
+                loop {
+                    days[day.num_days_from_monday() as usize] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = day.succ();
+                }
+            }
+        }
+    }
+    Ok(days)
+}
+
+impl Window {
+    pub fn parse(s: &str) -> Result<Window, String> {
+        let (days, times) = match s.trim().rsplit_once(' ') {
+            None => ([true; 7], s.trim()),
+            Some((days, times)) => (parse_days(days.trim())?, times),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM in {}", s))?;
+        Ok(Window {
+            days,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let weekday = now.weekday();
+        let today = self.days[weekday.num_days_from_monday() as usize];
+        let yesterday = self.days[weekday.pred().num_days_from_monday() as usize];
+        let minute = now.hour() * 60 + now.minute();
+        if self.start <= self.end {
+            today && self.start <= minute && minute < self.end
+        } else {
+            (today && minute >= self.start) || (yesterday && minute < self.end)
+        }
+    }
+}
+
+/// When ffmpeg may run, and how politely
+#[derive(Debug, Default)]
+pub struct Schedule {
+    /// No windows means encoding is always allowed
+    windows: Vec<Window>,
+    busy: Busy,
+    nice: Option<i64>,
+    ionice: Option<String>,
+}
+
+impl Schedule {
+    /// The keys read by `from_fields`
+    pub const KEYS: [&'static str; 4] = ["windows", "busy", "nice", "ionice"];
+
+    /// Read `windows`, `busy`, `nice` and `ionice` from the reencode config
+    pub fn from_fields(fields: &Fields) -> Result<Schedule, ConfigError> {
+        let windows = fields
+            .strings("windows")?
+            .unwrap_or_default()
+            .iter()
+            .map(|w| Window::parse(w).map_err(|e| fields.error("windows", e)))
+            .collect::<Result<_, _>>()?;
+        let ionice = fields.string("ionice")?;
+        if let Some(ionice) = &ionice {
+            let class = ionice.split(':').next().unwrap_or_default();
+            if !["idle", "best-effort", "realtime"].contains(&class) {
+                return Err(fields.error("ionice", "must be idle, best-effort or realtime"));
+            }
+        }
+        Ok(Schedule {
+            windows,
+            busy: Busy::from_fields(fields.object("busy")?)?,
+            nice: fields.int("nice")?,
+            ionice,
+        })
+    }
+
+    /// The same without windows or busy signals, for encodes that were asked for by hand
+    pub fn unrestricted(&self) -> Schedule {
+        Schedule {
+            windows: Vec::new(),
+            busy: Busy::default(),
+            nice: self.nice,
+            ionice: self.ionice.clone(),
+        }
+    }
+
+    /// Why ffmpeg shouldn't be running right now, if it shouldn't
+    pub fn blocked(&self) -> Option<String> {
+        let now = Local::now().naive_local();
+        if !self.windows.is_empty() && !self.windows.iter().any(|w| w.contains(now)) {
+            return Some("outside of the encoding windows".to_string());
+        }
+        self.busy.reason()
+    }
+
+    /// An `Exec` for `program`, run under `nice` and `ionice` if they are configured.
+    /// Both exec the program in place, so its pid can still be signalled directly.
+    pub fn command(&self, program: &str) -> Exec {
+        let mut args: Vec<String> = Vec::new();
+        if let Some(nice) = self.nice {
+            args.extend(["nice".to_string(), "-n".to_string(), nice.to_string()]);
+        }
+        if let Some(ionice) = &self.ionice {
+            // `idle`, `best-effort` or `best-effort:<level>`, as understood by ionice(1)
+            let (class, level) = match ionice.split_once(':') {
+                Some((class, level)) => (class, Some(level)),
+                None => (ionice.as_str(), None),
+            };
+            args.extend(["ionice".to_string(), "-c".to_string(), class.to_string()]);
+            if let Some(level) = level {
+                args.extend(["-n".to_string(), level.to_string()]);
+            }
+        }
+        args.push(program.to_string());
+        Exec::cmd(&args[0]).args(&args[1..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    /// `HH:MM` on a day of the week starting 2026-10-19, a Monday
+    fn at(day: Weekday, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, 19 + day.num_days_from_monday())
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn overnight_window_runs_past_midnight() {
+        let night = Window::parse("22:00-06:00").unwrap();
+        assert!(!night.contains(at(Weekday::Mon, "21:59")));
+        assert!(night.contains(at(Weekday::Mon, "22:00")));
+        assert!(night.contains(at(Weekday::Mon, "23:59")));
+        assert!(night.contains(at(Weekday::Tue, "00:00")));
+        assert!(night.contains(at(Weekday::Tue, "05:59")));
+        assert!(!night.contains(at(Weekday::Tue, "06:00")));
+        assert!(!night.contains(at(Weekday::Tue, "12:00")));
+    }
+
+    #[test]
+    fn day_ranges_wrap_around_the_week() {
+        let weekend = Window::parse("Fri-Mon 00:00-24:00").unwrap();
+        assert_eq!(weekend.days, [true, false, false, false, true, true, true]);
+        assert!(weekend.contains(at(Weekday::Sun, "12:00")));
+        assert!(weekend.contains(at(Weekday::Mon, "23:59")));
+        assert!(!weekend.contains(at(Weekday::Tue, "00:00")));
+    }
+
+    #[test]
+    fn overnight_window_belongs_to_the_day_it_starts() {
+        let nights = Window::parse("Fri-Mon 22:00-06:00").unwrap();
+        // Monday night runs into Tuesday morning
+        assert!(nights.contains(at(Weekday::Tue, "05:00")));
+        assert!(!nights.contains(at(Weekday::Tue, "23:00")));
+        // Thursday night isn't one of them
+        assert!(!nights.contains(at(Weekday::Fri, "05:00")));
+        assert!(nights.contains(at(Weekday::Fri, "22:30")));
+    }
+
+    #[test]
+    fn malformed_windows_are_rejected() {
+        for bad in [
+            "",
+            "22:00",
+            "Mon-Fri",
+            "25:00-06:00",
+            "22:00-6pm",
+            "Funday 01:00-02:00",
+            "Mon-Someday 01:00-02:00",
+        ] {
+            assert!(Window::parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::config::Config;
+use crate::reencode;
+use crate::store::{in_root, Candidate, FileRecord, JobRecord, RootRecord, Store};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// Fraction of a file's size assumed to be saved by reencoding it, until there is
+/// history to estimate from
+const DEFAULT_SAVINGS_RATIO: f64 = 0.4;
+
+fn resolution_bucket(height: Option<i32>) -> &'static str {
+    match height {
+        None => "unknown",
+        Some(h) if h >= 2160 => "2160p",
+        Some(h) if h >= 1440 => "1440p",
+        Some(h) if h >= 1080 => "1080p",
+        Some(h) if h >= 720 => "720p",
+        Some(h) if h >= 480 => "480p",
+        Some(_) => "SD",
+    }
+}
+
+/// One table of the report. Columns named `bytes` or `*_bytes` are shown human-readable.
+struct Section {
+    name: &'static str,
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Section {
+    fn new(name: &'static str, columns: Vec<&'static str>) -> Section {
+        Section {
+            name,
+            columns,
+            rows: Vec::new(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        Value::Array(
+            self.rows
+                .iter()
+                .map(|row| {
+                    let object: Map<String, Value> = self
+                        .columns
+                        .iter()
+                        .map(|c| c.to_string())
+                        .zip(row.iter().cloned())
+                        .collect();
+                    Value::Object(object)
+                })
+                .collect(),
+        )
+    }
+
+    fn print(&self) {
+        let cells: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .zip(self.columns.iter())
+                    .map(|(value, column)| format_cell(column, value))
+                    .collect()
+            })
+            .collect();
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                cells
+                    .iter()
+                    .map(|row| row[i].len())
+                    .chain([column.len()])
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        println!("{}", self.name);
+        let header: Vec<String> = self
+            .columns
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect();
+        println!("  {}", header.join("  ").trim_end());
+        for row in cells {
+            let line: Vec<String> = row
+                .iter()
+                .zip(widths.iter())
+                .enumerate()
+                .map(|(i, (cell, width))| {
+                    if i == 0 {
+                        format!("{:<width$}", cell, width = width)
+                    } else {
+                        format!("{:>width$}", cell, width = width)
+                    }
+                })
+                .collect();
+            println!("  {}", line.join("  "));
+        }
+        println!();
+    }
+}
+
+fn format_cell(column: &str, value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Number(n) if column == "bytes" || column.ends_with("_bytes") => {
+            human_bytes(n.as_f64().unwrap_or(0.0))
+        }
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() => format!("{:.2}", f),
+            _ => n.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Files and bytes in `paths`, grouped by `group`
+fn totals_by(
+    library: &[FileRecord],
+    name: &'static str,
+    column: &'static str,
+    group: impl Fn(&FileRecord) -> String,
+) -> Section {
+    let mut section = Section::new(name, vec![column, "files", "bytes"]);
+    let mut totals: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+    for entry in library.iter() {
+        let total = totals.entry(group(entry)).or_default();
+        total.0 += 1;
+        total.1 += entry.bytes;
+    }
+    let mut totals: Vec<(String, (i64, i64))> = totals.into_iter().collect();
+    totals.sort_by_key(|(_, (_, bytes))| -bytes);
+    for (grouping, (files, bytes)) in totals {
+        section
+            .rows
+            .push(vec![json!(grouping), json!(files), json!(bytes)]);
+    }
+    section
+}
+
+fn totals_by_root(library: &[FileRecord], roots: &[RootRecord]) -> Section {
+    let mut section = Section::new("Roots", vec!["root", "active", "files", "bytes"]);
+    for root in roots.iter() {
+        let files: Vec<&FileRecord> = library
+            .iter()
+            .filter(|f| in_root(&f.path, &root.root))
+            .collect();
+        let bytes: i64 = files.iter().map(|f| f.bytes).sum();
+        section.rows.push(vec![
+            json!(root.root),
+            json!(root.active),
+            json!(files.len()),
+            json!(bytes),
+        ]);
+    }
+    section
+}
+
+/// Succeeded jobs, month by month
+fn savings(succeeded: &[JobRecord]) -> Section {
+    let mut section = Section::new(
+        "Savings",
+        vec!["month", "files", "source_bytes", "saved_bytes"],
+    );
+    let mut months: BTreeMap<Option<String>, (i64, i64, i64)> = BTreeMap::new();
+    for job in succeeded.iter() {
+        let month = job.finished_at.map(|t| t.format("%Y-%m").to_string());
+        let total = months.entry(month).or_default();
+        total.0 += 1;
+        total.1 += job.source_bytes.unwrap_or(0);
+        total.2 += job.bytes_saved.unwrap_or(0);
+    }
+    for (month, (files, source_bytes, saved_bytes)) in months {
+        section.rows.push(vec![
+            json!(month),
+            json!(files),
+            json!(source_bytes),
+            json!(saved_bytes),
+        ]);
+    }
+    section
+}
+
+/// Fraction of the source size that past encodes have saved
+fn savings_ratio(succeeded: &[JobRecord]) -> f64 {
+    let saved: i64 = succeeded.iter().filter_map(|j| j.bytes_saved).sum();
+    let source: i64 = succeeded.iter().filter_map(|j| j.source_bytes).sum();
+    if source == 0 {
+        DEFAULT_SAVINGS_RATIO
+    } else {
+        saved as f64 / source as f64
+    }
+}
+
+/// Files still waiting to be reencoded, per root: the reencode queue as it stands
+fn backlog(pending: &[Candidate], succeeded: &[JobRecord], config: &Config) -> Section {
+    let ratio = savings_ratio(succeeded);
+    let mut section = Section::new(
+        "Reencode backlog",
+        vec!["root", "target", "files", "bytes", "estimated_saved_bytes"],
+    );
+    let roots = &config.reencode.roots;
+    let mut totals = vec![(0, 0); roots.len()];
+    for candidate in pending.iter() {
+        // Queued against the deepest root it is in, like the reencode module does
+        let deepest = roots
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| in_root(&candidate.path, &s.root))
+            .max_by_key(|(_, s)| s.root.trim_end_matches('/').len());
+        if let Some((i, _)) = deepest {
+            totals[i].0 += 1;
+            totals[i].1 += candidate.bytes;
+        }
+    }
+    for (settings, (files, bytes)) in roots.iter().zip(totals) {
+        if !settings.enabled {
+            continue;
+        }
+        section.rows.push(vec![
+            json!(settings.root),
+            json!(format!(
+                "{}/{}",
+                settings.target_codec, settings.target_extension
+            )),
+            json!(files),
+            json!(bytes),
+            json!((bytes as f64 * ratio) as i64),
+        ]);
+    }
+    section
+}
+
+/// Print library statistics, as aligned tables or (`format` = "json") one JSON object
+pub fn report(store: &mut dyn Store, config: &Config, format: &str) -> Result<(), Box<dyn Error>> {
+    let library = store.library()?;
+    let roots = store.roots()?;
+    let pending = reencode::pending(store, &config.reencode, i64::MAX)?;
+    let succeeded = store.jobs_in_state("succeeded")?;
+    let sections = [
+        totals_by(&library, "Codecs", "codec", |f| {
+            f.codec.clone().unwrap_or("unknown".to_string())
+        }),
+        totals_by(&library, "Resolutions", "resolution", |f| {
+            resolution_bucket(f.height).to_string()
+        }),
+        totals_by(&library, "Containers", "container", |f| {
+            f.extension.clone().unwrap_or("none".to_string())
+        }),
+        totals_by_root(&library, &roots),
+        backlog(&pending, &succeeded, config),
+        savings(&succeeded),
+    ];
+    match format {
+        "json" => {
+            let object: Map<String, Value> = sections
+                .iter()
+                .map(|s| (s.name.to_lowercase().replace(' ', "_"), s.to_json()))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&Value::Object(object))?);
+        }
+        _ => {
+            for section in sections.iter() {
+                section.print();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{file_record, MemoryStore, TestStore};
+
+    #[test]
+    fn backlog_is_the_reencode_queue() {
+        let mut store = MemoryStore::new();
+        store.add_root("/lib", true).unwrap();
+        store.add_root("/lib/phone", true).unwrap();
+        store
+            .set_service_config(
+                "reencode",
+                &json!({"profiles": {"phone": {"max_height": 720}}}),
+            )
+            .unwrap();
+        store
+            .set_root_config("/lib/phone", &json!({"reencode": {"profile": "phone"}}))
+            .unwrap();
+        store.add_file("/lib/a.avi", "h264", 10);
+        store.add_file("/lib/growing.avi", "h264", 20);
+        store.mark_unsettled("/lib/growing.avi").unwrap();
+        store.add_file("/lib/phone/1080p.mkv", "hevc", 30);
+        store
+            .store_file(&FileRecord {
+                height: Some(720),
+                width: Some(1280),
+                ..file_record("/lib/phone/720p.mkv", "hevc", 40)
+            })
+            .unwrap();
+        let config = Config::load(&mut store, None).unwrap();
+        let pending = reencode::pending(&mut store, &config.reencode, i64::MAX).unwrap();
+        let rows: Vec<(Value, Value, Value)> = backlog(&pending, &[], &config)
+            .rows
+            .into_iter()
+            .map(|row| (row[0].clone(), row[2].clone(), row[3].clone()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (json!("/lib"), json!(1), json!(10)),
+                (json!("/lib/phone"), json!(1), json!(30)),
+            ]
+        );
+    }
+}
@@ -1,14 +1,91 @@
-mod ffprobe;
+pub(crate) mod ffprobe;
 pub(crate) mod file;
+pub(crate) mod health;
+pub(crate) mod settle;
 
+use crate::config::{Config, ConfigError, Service};
+use crate::metrics;
+use crate::shutdown;
+use crate::store::{FileRecord, Store};
+use chrono::Local;
 use file::ScannedFile;
-use postgres::Client;
+use health::HealthCheck;
+use settle::Settle;
 use std::error::Error;
 use std::fs::{self, DirEntry};
 use std::path::Path;
+use std::time::Duration;
 
 type VoidResult = Result<(), Box<dyn Error>>;
 
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Settings of the scan module
+#[derive(Debug)]
+pub struct ScanConfig {
+    pub interval: Duration,
+    /// For paths outside of every root
+    settle: Settle,
+    health: HealthCheck,
+    roots: Vec<ScanRoot>,
+}
+
+#[derive(Debug)]
+struct ScanRoot {
+    root: String,
+    /// Set only if the root has an interval of its own
+    interval: Option<Duration>,
+    settle: Settle,
+    health: HealthCheck,
+}
+
+impl ScanConfig {
+    pub fn from_service(service: &Service) -> Result<ScanConfig, ConfigError> {
+        let keys: Vec<&str> = ["interval"]
+            .iter()
+            .chain(settle::KEYS.iter())
+            .chain(health::KEYS.iter())
+            .copied()
+            .collect();
+        service.only(&[], &keys)?;
+        let roots = service
+            .roots()
+            .into_iter()
+            .map(|(root, fields)| {
+                Ok(ScanRoot {
+                    root: root.to_string(),
+                    interval: if fields.overrides("interval") {
+                        fields.seconds("interval")?
+                    } else {
+                        None
+                    },
+                    settle: Settle::from_fields(&fields)?,
+                    health: HealthCheck::from_fields(&fields)?,
+                })
+            })
+            .collect::<Result<_, ConfigError>>()?;
+        Ok(ScanConfig {
+            interval: service
+                .global
+                .seconds("interval")?
+                .unwrap_or(DEFAULT_INTERVAL),
+            settle: Settle::from_fields(&service.global)?,
+            health: HealthCheck::from_fields(&service.global)?,
+            roots,
+        })
+    }
+
+    /// Settle and the health check as configured for the root `path` is in
+    fn checks_for(&self, path: &Path) -> (&Settle, &HealthCheck) {
+        self.roots
+            .iter()
+            .filter(|root| path.starts_with(&root.root))
+            .max_by_key(|root| root.root.len())
+            .map(|root| (&root.settle, &root.health))
+            .unwrap_or((&self.settle, &self.health))
+    }
+}
+
 // code from the Rust book
 fn visit_dirs(dir: &Path, visitor: &mut dyn FnMut(&DirEntry) -> VoidResult) -> VoidResult {
     for entry in fs::read_dir(dir)? {
@@ -29,22 +106,75 @@ fn visit_dirs(dir: &Path, visitor: &mut dyn FnMut(&DirEntry) -> VoidResult) -> V
     Ok(())
 }
 
-fn scan(root: &String, connection: &mut Client) -> VoidResult {
-    let mut visitor = |dir: &DirEntry| -> VoidResult {
-        let path = dir.path();
-        let path = path.as_path();
-        let file = ScannedFile::new(path, connection)?;
-        let result = file.store(connection);
-        match result {
-            Ok(i) => {
-                debug!("Wrote {} rows for {}", &i, &file.path);
-                Ok(())
+fn scan_file(
+    path: &Path,
+    settle: &Settle,
+    health: &HealthCheck,
+    videos: &[String],
+    store: &mut dyn Store,
+) -> VoidResult {
+    if shutdown::requested() {
+        return Err("shutting down".into());
+    }
+    if let Some(reason) = settle.unsettled(path) {
+        debug!("Skipping {:?} until it settles: {}", path, reason);
+        // Keep reencode away from a known file that is being rewritten
+        store.mark_unsettled(&format!("{}", path.display()))?;
+        return Ok(());
+    }
+    let file = ScannedFile::new(path, store)?;
+    let result = file.store(store);
+    match result {
+        Ok(()) => {
+            debug!("Stored {}", &file.record.path);
+            metrics::FILE_COUNTER.with_label_values(&["scan"]).inc();
+            if health_due(&file.record, health, videos, store)? {
+                check_health(&file.record.path, health, store)?;
             }
-            Err(e) => {
-                warn!("Error {} while trying to store file {:?}", &e, &file);
-                Ok(())
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Error {} while trying to store file {:?}", &e, &file);
+            Ok(())
+        }
+    }
+}
+
+/// Whether `file` is a video that hasn't been checked since it last changed. Subtitles,
+/// artwork and the like would all look damaged to ffmpeg.
+fn health_due(
+    file: &FileRecord,
+    health: &HealthCheck,
+    videos: &[String],
+    store: &mut dyn Store,
+) -> Result<bool, Box<dyn Error>> {
+    let video = file.extension.as_ref().is_some_and(|e| videos.contains(e));
+    Ok(health.enabled() && video && store.health(&file.path)?.is_none())
+}
+
+fn check_health(path: &str, health: &HealthCheck, store: &mut dyn Store) -> VoidResult {
+    debug!("Checking the health of {}", path);
+    match health.check(path) {
+        Ok(record) => {
+            if let Some(errors) = &record.errors {
+                warn!(
+                    "{} is damaged: {}",
+                    path,
+                    errors.lines().next().unwrap_or("")
+                );
             }
+            store.set_health(&record)?;
+            metrics::FILE_COUNTER.with_label_values(&["health"]).inc();
         }
+        Err(e) => warn!("Failed to check the health of {}: {}", path, e),
+    }
+    Ok(())
+}
+
+fn scan(root: &String, settle: &Settle, health: &HealthCheck, store: &mut dyn Store) -> VoidResult {
+    let videos = store.video_extensions()?;
+    let mut visitor = |dir: &DirEntry| -> VoidResult {
+        scan_file(dir.path().as_path(), settle, health, &videos, store)
     };
     let root_path = Path::new(root);
     if root_path.is_dir() {
@@ -56,21 +186,182 @@ fn scan(root: &String, connection: &mut Client) -> VoidResult {
     Ok(())
 }
 
+/// Scan a single file, or everything beneath a directory
+pub fn scan_path(path: &Path, config: &Config, store: &mut dyn Store) -> VoidResult {
+    let (settle, health) = config.scan.checks_for(path);
+    if path.is_file() {
+        let videos = store.video_extensions()?;
+        scan_file(path, settle, health, &videos, store)
+    } else {
+        scan(&format!("{}", path.display()), settle, health, store)
+    }
+}
+
+// Roots with their own scan interval are only rescanned once it has elapsed
+fn scan_due(root: &str, interval: Duration, store: &mut dyn Store) -> bool {
+    let last_scanned = store
+        .roots()
+        .unwrap()
+        .into_iter()
+        .find(|r| r.root == root)
+        .and_then(|r| r.last_scanned);
+    match (last_scanned, chrono::Duration::from_std(interval)) {
+        (Some(last_scanned), Ok(interval)) => last_scanned + interval <= Local::now(),
+        _ => true,
+    }
+}
+
 pub struct Scan {}
 impl crate::module::Module for Scan {
     fn module_name(&self) -> &str {
         "scan"
     }
-    fn module_iteration(&self, connection: &mut Client) {
+    fn interval(&self, config: &Config) -> Duration {
+        config.scan.interval
+    }
+    fn module_iteration(&self, store: &mut dyn Store, config: &Config) {
         let mut i = 0;
-        for row in &connection
-            .query("SELECT root FROM roots WHERE active ORDER BY root ASC", &[])
-            .unwrap()
-        {
-            let root: String = row.get(0);
-            scan(&root, connection).unwrap();
+        for root in config.scan.roots.iter() {
+            if let Some(interval) = root.interval {
+                if !scan_due(&root.root, interval, store) {
+                    debug!("Root {} was scanned recently; skipping", &root.root);
+                    continue;
+                }
+            }
+            store.mark_scanned(&root.root).unwrap();
+            if let Err(e) = scan(&root.root, &root.settle, &root.health, store) {
+                warn!("Stopped scanning {}: {}", &root.root, e);
+                if shutdown::requested() {
+                    break;
+                }
+                continue;
+            }
             i += 1;
         }
         info!("Scanned {} roots", &i);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::Module;
+    use crate::store::memory::{backend_tests, file_record, scratch_dir, MemoryStore, TestStore};
+    use serde_json::json;
+
+    backend_tests!(files_found_again_are_no_longer_missing);
+
+    #[test]
+    fn files_being_written_are_left_until_they_settle() {
+        let dir = scratch_dir("scan-settle");
+        let known = dir.join("known.mp4");
+        let new = dir.join("new.mp4");
+        fs::write(&known, b"still growing").unwrap();
+        fs::write(&new, b"just arrived").unwrap();
+        let mut store = MemoryStore::new();
+        store.add_file(known.to_str().unwrap(), "h264", 5);
+        let config = Config::load(&mut store, None).unwrap();
+        scan_path(&dir, &config, &mut store).unwrap();
+        let known = store.row(known.to_str().unwrap()).unwrap();
+        assert!(known.settled_at.is_none());
+        assert!(store.row(new.to_str().unwrap()).is_none());
+    }
+
+    #[test]
+    fn roots_with_their_own_interval_wait_for_it() {
+        let mut store = MemoryStore::new();
+        store.add_root("/media/archive", true).unwrap();
+        store.add_root("/media/movies", true).unwrap();
+        store
+            .set_root_config("/media/archive", &json!({"scan": {"interval": 86400}}))
+            .unwrap();
+        assert!(scan_due(
+            "/media/archive",
+            Duration::from_secs(86400),
+            &mut store
+        ));
+        store.mark_scanned("/media/archive").unwrap();
+        assert!(!scan_due(
+            "/media/archive",
+            Duration::from_secs(86400),
+            &mut store
+        ));
+        assert!(scan_due("/media/archive", Duration::ZERO, &mut store));
+
+        let scanned = store.roots().unwrap()[0].last_scanned;
+        let config = Config::load(&mut store, None).unwrap();
+        Scan {}.module_iteration(&mut store, &config);
+        let roots = store.roots().unwrap();
+        assert_eq!(roots[0].last_scanned, scanned);
+        assert!(roots[1].last_scanned.is_some());
+    }
+
+    #[test]
+    fn root_intervals_from_the_database_survive_the_file() {
+        let mut store = MemoryStore::new();
+        store.add_root("/media/archive", true).unwrap();
+        store
+            .set_root_config("/media/archive", &json!({"scan": {"interval": 86400}}))
+            .unwrap();
+        let file = scratch_dir("scan-file-root").join("config.toml");
+        fs::write(
+            &file,
+            "[[roots]]\nroot = \"/media/archive\"\n[roots.scan]\nsettle_minutes = 5\n",
+        )
+        .unwrap();
+        let config = Config::load(&mut store, Some(&file)).unwrap();
+        assert_eq!(
+            config.scan.roots[0].interval,
+            Some(Duration::from_secs(86400))
+        );
+    }
+
+    fn files_found_again_are_no_longer_missing(store: &mut dyn TestStore) {
+        let path = scratch_dir(&format!("scan-found-{}", store.kind())).join("back.mp4");
+        fs::write(&path, b"video").unwrap();
+        let path_s = path.to_str().unwrap();
+        store.store_file(&file_record(path_s, "h264", 5)).unwrap();
+        store.mark_missing(&[path_s.to_string()]).unwrap();
+        assert!(store.row(path_s).unwrap().missing_since.is_some());
+        // Unchanged, so it is not probed again
+        let file = ScannedFile::new(&path, store).unwrap();
+        file.store(store).unwrap();
+        let row = store.row(path_s).unwrap();
+        assert!(row.missing_since.is_none());
+        assert!(row.settled_at.is_some());
+    }
+
+    #[test]
+    fn only_videos_are_health_checked() {
+        let mut store = MemoryStore::new();
+        store
+            .set_service_config("scan", &json!({"health_check": "full"}))
+            .unwrap();
+        let config = Config::load(&mut store, None).unwrap();
+        let (_, health) = config.scan.checks_for(Path::new("/media/movies"));
+        let videos = store.video_extensions().unwrap();
+        let mut due = |path: &str| {
+            health_due(&file_record(path, "h264", 5), health, &videos, &mut store).unwrap()
+        };
+        assert!(due("/media/movies/a.mkv"));
+        assert!(!due("/media/movies/a.nfo"));
+        assert!(!due("/media/movies/a.srt"));
+        assert!(!due("/media/movies/poster.jpg"));
+    }
+
+    #[test]
+    fn changed_files_are_checked_again() {
+        let mut store = MemoryStore::new();
+        store.add_file("/media/movies/a.mkv", "h264", 5);
+        let health = crate::store::HealthRecord {
+            path: "/media/movies/a.mkv".to_string(),
+            status: "damaged".to_string(),
+            errors: Some("error while decoding MB 3 4".to_string()),
+            checked_at: Local::now(),
+        };
+        store.set_health(&health).unwrap();
+        assert_eq!(store.damaged().unwrap(), vec![health]);
+        store.add_file("/media/movies/a.mkv", "h264", 6);
+        assert!(store.health("/media/movies/a.mkv").unwrap().is_none());
+    }
+}
@@ -4,12 +4,175 @@ use std::str::FromStr;
 use subprocess::Exec;
 use subprocess::Redirection;
 
+/// SMPTE ST 2086 mastering display colour volume: CIE 1931 x,y chromaticities and
+/// luminance in cd/m²
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MasteringDisplay {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white_point: (f64, f64),
+    pub min_luminance: f64,
+    pub max_luminance: f64,
+}
+
+/// Maximum content and frame-average light levels, in cd/m²
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContentLight {
+    pub max_content: i64,
+    pub max_average: i64,
+}
+
+/// From a stream's Dolby Vision configuration record
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DolbyVision {
+    pub profile: i64,
+    /// What the base layer is without the Dolby Vision layer: 0 for nothing usable,
+    /// as in profile 5
+    pub compatibility_id: i64,
+}
+
 #[derive(Default)]
 pub struct ProbedInfo {
     pub codec: Option<String>,
     pub height: Option<i32>,
     pub width: Option<i32>,
     pub bit_rate: Option<f32>,
+    /// Seconds
+    pub duration: Option<f64>,
+    /// Frames per second
+    pub frame_rate: Option<f64>,
+    pub pix_fmt: Option<String>,
+    pub bit_depth: Option<i32>,
+    pub color_primaries: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_space: Option<String>,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light: Option<ContentLight>,
+    pub dolby_vision: Option<DolbyVision>,
+}
+
+impl ProbedInfo {
+    /// The probe as a JSON object, for keeping alongside job history
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "codec": self.codec,
+            "height": self.height,
+            "width": self.width,
+            "kbps": self.bit_rate,
+            "duration": self.duration,
+            "frame_rate": self.frame_rate,
+            "pix_fmt": self.pix_fmt,
+            "bit_depth": self.bit_depth,
+            "color_primaries": self.color_primaries,
+            "color_transfer": self.color_transfer,
+            "color_space": self.color_space,
+            "hdr": self.hdr_format(),
+            "mastering_display": self.mastering_display.as_ref().map(|m| serde_json::json!({
+                "red": [m.red.0, m.red.1],
+                "green": [m.green.0, m.green.1],
+                "blue": [m.blue.0, m.blue.1],
+                "white_point": [m.white_point.0, m.white_point.1],
+                "min_luminance": m.min_luminance,
+                "max_luminance": m.max_luminance,
+            })),
+            "max_cll": self.content_light.as_ref().map(|c| c.max_content),
+            "max_fall": self.content_light.as_ref().map(|c| c.max_average),
+        })
+    }
+
+    /// Dolby Vision, HDR10 or HLG; `None` for SDR
+    pub fn hdr_format(&self) -> Option<String> {
+        if let Some(dolby_vision) = &self.dolby_vision {
+            return Some(format!("Dolby Vision profile {}", dolby_vision.profile));
+        }
+        match self.color_transfer.as_deref() {
+            Some("smpte2084") => Some("HDR10".to_string()),
+            Some("arib-std-b67") => Some("HLG".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Fill in what's known from a stream's or frame's `side_data_list`
+    fn read_side_data(&mut self, side_data: Option<&serde_json::Value>) {
+        for item in side_data.and_then(|s| s.as_array()).into_iter().flatten() {
+            match item["side_data_type"].as_str() {
+                Some("Mastering display metadata") => {
+                    self.mastering_display = mastering_display(item);
+                }
+                Some("Content light level metadata") => {
+                    self.content_light = Some(ContentLight {
+                        max_content: item["max_content"].as_i64().unwrap_or(0),
+                        max_average: item["max_average"].as_i64().unwrap_or(0),
+                    });
+                }
+                Some("DOVI configuration record") => {
+                    self.dolby_vision = item["dv_profile"].as_i64().map(|profile| DolbyVision {
+                        profile,
+                        compatibility_id: item["dv_bl_signal_compatibility_id"]
+                            .as_i64()
+                            .unwrap_or(0),
+                    });
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// A number that ffprobe may give as a "numerator/denominator" string
+pub(crate) fn rational(value: &serde_json::Value) -> Option<f64> {
+    if let Some(n) = value.as_f64() {
+        return Some(n);
+    }
+    let text = value.as_str()?;
+    match text.split_once('/') {
+        Some((n, d)) => Some(f64::from_str(n).ok()? / f64::from_str(d).ok()?),
+        None => f64::from_str(text).ok(),
+    }
+}
+
+fn mastering_display(item: &serde_json::Value) -> Option<MasteringDisplay> {
+    let point = |name: &str| -> Option<(f64, f64)> {
+        Some((
+            rational(&item[format!("{}_x", name)])?,
+            rational(&item[format!("{}_y", name)])?,
+        ))
+    };
+    Some(MasteringDisplay {
+        red: point("red")?,
+        green: point("green")?,
+        blue: point("blue")?,
+        white_point: point("white_point")?,
+        min_luminance: rational(&item["min_luminance"])?,
+        max_luminance: rational(&item["max_luminance"])?,
+    })
+}
+
+/// Bits per sample from the stream, or else from its pixel format, e.g. yuv420p10le
+fn bit_depth(stream: &serde_json::Value) -> Option<i32> {
+    if let Some(bits) = stream["bits_per_raw_sample"]
+        .as_str()
+        .and_then(|b| i32::from_str(b).ok())
+    {
+        return Some(bits);
+    }
+    lazy_static! {
+        static ref DEPTH: Regex = Regex::new(r"p(\d+)(le|be)$").unwrap();
+    }
+    let pix_fmt = stream["pix_fmt"].as_str()?;
+    match DEPTH.captures(pix_fmt) {
+        Some(captures) => i32::from_str(&captures[1]).ok(),
+        None => Some(8),
+    }
+}
+
+/// A colour property, unless ffprobe doesn't know it
+fn color(stream: &serde_json::Value, key: &str) -> Option<String> {
+    stream[key]
+        .as_str()
+        .filter(|c| *c != "unknown" && *c != "reserved")
+        .map(str::to_string)
 }
 
 type ProbeInfoResult = Result<ProbedInfo, Box<dyn Error>>;
@@ -38,17 +201,44 @@ impl ProbeResult for serde_json::Value {
         };
         let height = option_downcast(self.get("height").unwrap().as_i64());
         let width = option_downcast(self.get("width").unwrap().as_i64());
-        Ok(ProbedInfo {
+        let duration = self
+            .get("duration")
+            .and_then(|d| d.as_str())
+            .and_then(|d| f64::from_str(d).ok());
+        let mut info = ProbedInfo {
             codec,
             height,
             width,
             bit_rate,
-        })
+            duration,
+            frame_rate: ["avg_frame_rate", "r_frame_rate"]
+                .iter()
+                .filter_map(|key| rational(&self[*key]))
+                .find(|rate| rate.is_finite() && *rate > 0.0),
+            pix_fmt: self["pix_fmt"].as_str().map(str::to_string),
+            bit_depth: bit_depth(self),
+            color_primaries: color(self, "color_primaries"),
+            color_transfer: color(self, "color_transfer"),
+            color_space: color(self, "color_space"),
+            ..Default::default()
+        };
+        info.read_side_data(self.get("side_data_list"));
+        Ok(info)
     }
 }
 
 pub fn probe(path: &String) -> ProbeInfoResult {
-    ffprobe_data(path).unpack_probe_result()
+    let mut info = ffprobe_data(path).unpack_probe_result()?;
+    // Many containers only carry HDR10's static metadata in the frames
+    if info.color_transfer.as_deref() == Some("smpte2084")
+        && (info.mastering_display.is_none() || info.content_light.is_none())
+    {
+        let mut frame = ProbedInfo::default();
+        frame.read_side_data(first_frame_side_data(path).as_ref());
+        info.mastering_display = info.mastering_display.or(frame.mastering_display);
+        info.content_light = info.content_light.or(frame.content_light);
+    }
+    Ok(info)
 }
 
 fn option_downcast(value: Option<i64>) -> Option<i32> {
@@ -102,6 +292,7 @@ fn ffprobe_data(path: &String) -> Option<serde_json::Value> {
     trace!("ffprobe {}", &path);
     let captured = Exec::cmd("ffprobe")
         .arg("-show_streams")
+        .arg("-show_format")
         .arg("-loglevel")
         .arg("error")
         .arg("-print_format")
@@ -124,9 +315,108 @@ fn ffprobe_data(path: &String) -> Option<serde_json::Value> {
                 break;
             }
         }
+        // Matroska streams carry no duration of their own; fall back to the container's
+        if let Some(stream) = target_stream.as_mut() {
+            if stream.get("duration").is_none() {
+                if let Some(duration) = parsed.pointer("/format/duration") {
+                    stream["duration"] = duration.to_owned();
+                }
+            }
+        }
         target_stream
     } else {
         warn!("ffprobe non-success: {}", &captured.stderr_str());
         None
     }
 }
+
+/// The side data of the first video frame
+fn first_frame_side_data(path: &String) -> Option<serde_json::Value> {
+    trace!("ffprobe frame side data {}", &path);
+    let captured = Exec::cmd("ffprobe")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v:0")
+        .arg("-read_intervals")
+        .arg("%+#1")
+        .arg("-show_entries")
+        .arg("frame=side_data_list")
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .capture()
+        .ok()?;
+    if !captured.success() {
+        warn!("ffprobe non-success: {}", &captured.stderr_str());
+        return None;
+    }
+    let parsed: serde_json::Value = serde_json::from_str(&captured.stdout_str()).ok()?;
+    parsed.pointer("/frames/0/side_data_list").cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_hdr_metadata() {
+        let stream = json!({
+            "codec_name": "hevc",
+            "width": 3840,
+            "height": 2160,
+            "pix_fmt": "yuv420p10le",
+            "color_space": "bt2020nc",
+            "color_transfer": "smpte2084",
+            "color_primaries": "bt2020",
+            "side_data_list": [
+                {
+                    "side_data_type": "DOVI configuration record",
+                    "dv_profile": 8,
+                    "dv_bl_signal_compatibility_id": 1
+                },
+                {
+                    "side_data_type": "Mastering display metadata",
+                    "red_x": "34000/50000",
+                    "red_y": "16000/50000",
+                    "green_x": "13250/50000",
+                    "green_y": "34500/50000",
+                    "blue_x": "7500/50000",
+                    "blue_y": "3000/50000",
+                    "white_point_x": "15635/50000",
+                    "white_point_y": "16450/50000",
+                    "min_luminance": "50/10000",
+                    "max_luminance": "10000000/10000"
+                },
+                {
+                    "side_data_type": "Content light level metadata",
+                    "max_content": 1000,
+                    "max_average": 400
+                }
+            ]
+        });
+        let info = stream.unpack_probe_result().unwrap();
+        assert_eq!(info.bit_depth, Some(10));
+        assert_eq!(info.color_primaries.as_deref(), Some("bt2020"));
+        assert_eq!(info.hdr_format().as_deref(), Some("Dolby Vision profile 8"));
+        let display = info.mastering_display.unwrap();
+        assert_eq!(display.red, (0.68, 0.32));
+        assert_eq!(display.max_luminance, 1000.0);
+        assert_eq!(
+            info.content_light,
+            Some(ContentLight {
+                max_content: 1000,
+                max_average: 400
+            })
+        );
+
+        let sdr =
+            json!({"codec_name": "h264", "width": 1920, "height": 1080, "pix_fmt": "yuv420p"});
+        let info = sdr.unpack_probe_result().unwrap();
+        assert_eq!(info.bit_depth, Some(8));
+        assert_eq!(info.hdr_format(), None);
+    }
+}
@@ -1,7 +1,7 @@
 use crate::scan::ffprobe;
+use crate::store::{FileRecord, Store};
 use chrono::offset::Local;
 use chrono::DateTime;
-use postgres::row::Row;
 use sha256::Sha256Digest;
 use std::cmp::{max, min};
 use std::error::Error;
@@ -20,53 +20,45 @@ enum Operation {
 
 #[derive(Debug)]
 pub struct ScannedFile {
-    hash: String,
-    pub path: String,
-    codec: Option<String>,
-    height: Option<i32>,
-    width: Option<i32>,
-    kbps: Option<f32>,
-    extension: Option<String>,
-    pub bytes: i64,
-    last_modified: DateTime<Local>,
+    pub record: FileRecord,
     operation: Option<Operation>,
 }
 
 impl ScannedFile {
-    pub fn new(
-        path: &Path,
-        connection: &mut postgres::Client,
-    ) -> Result<ScannedFile, Box<dyn Error>> {
+    pub fn new(path: &Path, store: &mut dyn Store) -> Result<ScannedFile, Box<dyn Error>> {
         let mut file = File::open(path)?;
         let path_string = format!("{}", path.display());
         let last_modified = last_modified(&file)?;
-        let existing_files = connection.query("SELECT hash, last_modified, codec, height, width, kbps, extension, bytes FROM paths WHERE path = $1", &[&path_string])
-            .or(Err("failed to query for known paths"))?;
-        Self::new_from_result(&mut file, path_string, last_modified, &existing_files)
+        let existing = store
+            .file(&path_string)
+            .map_err(|e| format!("failed to query for known paths: {}", e))?;
+        Self::new_from_record(&mut file, path_string, last_modified, existing)
     }
-    fn new_from_result(
+    fn new_from_record(
         file: &mut File,
         path_string: String,
         last_modified: DateTime<Local>,
-        existing_files: &Vec<Row>,
+        existing: Option<FileRecord>,
     ) -> Result<ScannedFile, Box<dyn Error>> {
-        if existing_files.is_empty() {
-            Self::new_from_file(file, path_string, last_modified, Some(Operation::INSERT))
-        } else {
-            let found = existing_files.first().expect("found no files");
-            let db_last_modified: DateTime<Local> = found.get("last_modified");
-            // Postgres timestamps are less precise than I get from the OS here, so look only at whole ms resolution
-            let delta = last_modified - db_last_modified;
-            let delta_ms = delta.num_milliseconds();
-            if delta_ms < 1 {
-                debug!("Last modified in the DB is newer or same; no change");
-                Self::new_from_row(found, path_string, None)
-            } else {
-                debug!(
-                    "Last modified in the DB is older ({} < {}); needs update",
-                    &db_last_modified, &last_modified
-                );
-                Self::new_from_file(file, path_string, last_modified, Some(Operation::UPDATE))
+        match existing {
+            None => Self::new_from_file(file, path_string, last_modified, Some(Operation::INSERT)),
+            Some(found) => {
+                // Postgres timestamps are less precise than I get from the OS here, so look only at whole ms resolution
+                let delta = last_modified - found.last_modified;
+                let delta_ms = delta.num_milliseconds();
+                if delta_ms < 1 && found.bytes == file_bytes(file) {
+                    debug!("Last modified in the DB is newer or same and size matches; no change");
+                    Ok(ScannedFile {
+                        record: found,
+                        operation: None,
+                    })
+                } else {
+                    debug!(
+                        "Last modified in the DB is older ({} < {}) or size changed; needs update",
+                        &found.last_modified, &last_modified
+                    );
+                    Self::new_from_file(file, path_string, last_modified, Some(Operation::UPDATE))
+                }
             }
         }
     }
@@ -82,53 +74,25 @@ impl ScannedFile {
         let extension = file_extension(&path);
         let bytes = file_bytes(file);
         Ok(ScannedFile {
-            hash,
-            path,
-            codec: info.codec,
-            height: info.height,
-            width: info.width,
-            kbps: info.bit_rate,
-            extension,
-            bytes,
-            last_modified,
-            operation,
-        })
-    }
-    fn new_from_row(
-        row: &Row,
-        path_string: String,
-        operation: Option<Operation>,
-    ) -> Result<ScannedFile, Box<dyn Error>> {
-        let hash = row.get("hash");
-        let last_modified = row.get("last_modified");
-        let codec = row.get("codec");
-        let height = row.get("height");
-        let width = row.get("width");
-        let kbps = row.get("kbps");
-        let extension = row.get("extension");
-        let bytes = row.get("bytes");
-        let path = path_string;
-        Ok(ScannedFile {
-            hash,
-            path,
-            codec,
-            height,
-            width,
-            kbps,
-            extension,
-            bytes,
-            last_modified,
+            record: FileRecord {
+                hash,
+                path,
+                codec: info.codec,
+                height: info.height,
+                width: info.width,
+                kbps: info.bit_rate,
+                extension,
+                bytes,
+                last_modified,
+            },
             operation,
         })
     }
-    pub fn store(
-        &self,
-        connection: &mut postgres::Client,
-    ) -> core::result::Result<u64, postgres::Error> {
+    pub fn store(&self, store: &mut dyn Store) -> Result<(), Box<dyn Error>> {
         match &self.operation {
-            Some(Operation::INSERT) => connection.execute("INSERT INTO paths (hash, path, last_modified, codec, height, width, kbps, extension, bytes) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)", &[&self.hash, &self.path, &self.last_modified, &self.codec, &self.height, &self.width, &self.kbps, &self.extension, &self.bytes]),
-            Some(Operation::UPDATE) => connection.execute("UPDATE paths SET (hash, last_modified) = ($1, $3) WHERE path = $2", &[&self.hash, &self.path, &self.last_modified]),
-            None => Ok(0)
+            Some(Operation::INSERT) | Some(Operation::UPDATE) => store.store_file(&self.record),
+            // Unchanged since the last scan, and settled now if it wasn't then
+            None => store.mark_settled(&self.record.path),
         }
     }
 }
@@ -136,10 +100,7 @@ impl ScannedFile {
 fn file_extension(path: &String) -> Option<String> {
     match Path::new(&path).extension() {
         None => None,
-        Some(os_str) => match os_str.to_os_string().into_string() {
-            Ok(string) => Some(string),
-            Err(_) => None,
-        },
+        Some(os_str) => os_str.to_os_string().into_string().ok(),
     }
 }
 
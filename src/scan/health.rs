@@ -0,0 +1,166 @@
+use crate::config::{ConfigError, Fields};
+use crate::scan::ffprobe;
+use crate::store::HealthRecord;
+use chrono::Local;
+use std::error::Error;
+use subprocess::{Exec, NullFile, Redirection};
+
+/// The keys read by `HealthCheck::from_fields`
+pub const KEYS: [&str; 3] = ["health_check", "health_samples", "health_sample_seconds"];
+
+const DEFAULT_SAMPLES: u64 = 5;
+const DEFAULT_SAMPLE_SECONDS: u64 = 10;
+/// How much of ffmpeg's complaints about a file to keep
+const ERRORS_EXCERPT_LENGTH: usize = 4000;
+
+/// How much of a file to decode when looking for damage
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Off,
+    Full,
+    /// `samples` stretches of `seconds` each, spread evenly through the file
+    Sampled {
+        samples: u64,
+        seconds: u64,
+    },
+}
+
+/// Decoding a file with ffmpeg to find damage that ffprobe doesn't notice
+#[derive(Debug)]
+pub struct HealthCheck {
+    mode: Mode,
+}
+
+impl HealthCheck {
+    /// Read `health_check` (off, full or sampled), `health_samples` and
+    /// `health_sample_seconds`
+    pub fn from_fields(fields: &Fields) -> Result<HealthCheck, ConfigError> {
+        let mode = fields
+            .parse("health_check", |v| match v.as_str() {
+                Some("off") => Ok(Mode::Off),
+                Some("full") => Ok(Mode::Full),
+                Some("sampled") => Ok(Mode::Sampled {
+                    samples: 0,
+                    seconds: 0,
+                }),
+                _ => Err("must be off, full or sampled".to_string()),
+            })?
+            .unwrap_or(Mode::Off);
+        let samples = fields.count("health_samples")?.unwrap_or(DEFAULT_SAMPLES);
+        let seconds = fields
+            .count("health_sample_seconds")?
+            .unwrap_or(DEFAULT_SAMPLE_SECONDS);
+        if samples == 0 {
+            return Err(fields.error("health_samples", "must be at least 1"));
+        }
+        if seconds == 0 {
+            return Err(fields.error("health_sample_seconds", "must be at least 1"));
+        }
+        Ok(HealthCheck {
+            mode: match mode {
+                Mode::Sampled { .. } => Mode::Sampled { samples, seconds },
+                mode => mode,
+            },
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.mode != Mode::Off
+    }
+
+    /// Decode `path`, or the samples of it, and say what ffmpeg thought
+    pub fn check(&self, path: &str) -> Result<HealthRecord, Box<dyn Error>> {
+        let windows = match self.mode {
+            Mode::Sampled { samples, seconds } => match ffprobe::probe(&path.to_string())?.duration
+            {
+                Some(duration) => sample_windows(duration, samples, seconds),
+                // Without a duration there's nowhere to sample from
+                None => vec![None],
+            },
+            _ => vec![None],
+        };
+        let mut errors = String::new();
+        for window in windows {
+            let found = decode(path, window)?;
+            if found.is_empty() {
+                continue;
+            }
+            if let Some((start, _)) = window {
+                errors.push_str(&format!("at {:.0}s:\n", start));
+            }
+            errors.push_str(&found);
+        }
+        let damaged = !errors.is_empty();
+        Ok(HealthRecord {
+            path: path.to_string(),
+            status: if damaged { "damaged" } else { "ok" }.to_string(),
+            errors: damaged.then(|| excerpt(&errors)),
+            checked_at: Local::now(),
+        })
+    }
+}
+
+/// Where to start decoding, and for how long, to take `samples` samples of `seconds`
+/// from a file of `duration` seconds, each centred on an even division of it
+pub(crate) fn sample_windows(duration: f64, samples: u64, seconds: u64) -> Vec<Option<(f64, u64)>> {
+    if duration <= (samples * seconds) as f64 {
+        return vec![None];
+    }
+    (1..=samples)
+        .map(|i| {
+            let centre = duration * i as f64 / (samples + 1) as f64;
+            Some(((centre - seconds as f64 / 2.0).max(0.0), seconds))
+        })
+        .collect()
+}
+
+/// The errors ffmpeg reports decoding `path`, all of it or the `(start, seconds)` window
+fn decode(path: &str, window: Option<(f64, u64)>) -> Result<String, Box<dyn Error>> {
+    let mut command = Exec::cmd("ffmpeg").arg("-nostdin").arg("-v").arg("error");
+    if let Some((start, seconds)) = window {
+        command = command
+            .arg("-ss")
+            .arg(format!("{:.3}", start))
+            .arg("-t")
+            .arg(seconds.to_string());
+    }
+    trace!("Checking the health of {}", path);
+    let captured = command
+        .arg("-i")
+        .arg(path)
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .stdout(NullFile)
+        .stderr(Redirection::Pipe)
+        .capture()?;
+    let mut errors = captured.stderr_str();
+    if !captured.success() {
+        errors.push_str(&format!("ffmpeg exited with {:?}\n", captured.exit_status));
+    }
+    Ok(errors)
+}
+
+/// The start of `errors`, which is enough to see what's wrong
+fn excerpt(errors: &str) -> String {
+    let mut end = errors.len().min(ERRORS_EXCERPT_LENGTH);
+    while !errors.is_char_boundary(end) {
+        end -= 1;
+    }
+    errors[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_spread_through_the_file() {
+        assert_eq!(
+            sample_windows(600.0, 2, 10),
+            vec![Some((195.0, 10)), Some((395.0, 10))]
+        );
+        // Sampling most of a short file is no quicker than decoding it all
+        assert_eq!(sample_windows(30.0, 5, 10), vec![None]);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config::{ConfigError, Fields};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// How long a file must go unmodified before it is scanned, unless `settle_minutes` is set
+const DEFAULT_SETTLE_MINUTES: u64 = 5;
+
+/// The keys read by `Settle::from_fields`
+pub const KEYS: [&str; 2] = ["settle_minutes", "check_open_files"];
+
+/// When a file counts as finished rather than still being written by a downloader or copy
+#[derive(Debug)]
+pub struct Settle {
+    period: Duration,
+    check_open: bool,
+}
+
+impl Settle {
+    /// Read `settle_minutes` and `check_open_files`
+    pub fn from_fields(fields: &Fields) -> Result<Settle, ConfigError> {
+        let minutes = fields
+            .count("settle_minutes")?
+            .unwrap_or(DEFAULT_SETTLE_MINUTES);
+        Ok(Settle {
+            period: Duration::from_secs(minutes * 60),
+            check_open: fields.bool("check_open_files")?.unwrap_or(false),
+        })
+    }
+
+    /// Why `path` may still be being written, if it may
+    pub fn unsettled(&self, path: &Path) -> Option<String> {
+        let modified = match path.metadata().and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => return Some(e.to_string()),
+        };
+        // A modification time in the future is as suspicious as a recent one
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or(Duration::ZERO);
+        if age < self.period {
+            return Some(format!("modified {}s ago", age.as_secs()));
+        }
+        if self.check_open {
+            if let Some(pid) = opened_by(path) {
+                return Some(format!("open in process {}", pid));
+            }
+        }
+        None
+    }
+}
+
+/// A process other than this one that has `path` open, found through /proc. Processes
+/// of other users are only visible when running as root.
+fn opened_by(path: &Path) -> Option<u32> {
+    let path = path.canonicalize().ok()?;
+    let own = std::process::id();
+    for process in fs::read_dir("/proc").ok()?.flatten() {
+        let pid = match process
+            .file_name()
+            .to_str()
+            .and_then(|p| p.parse::<u32>().ok())
+        {
+            Some(pid) if pid != own => pid,
+            _ => continue,
+        };
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        if fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|target| target == path))
+        {
+            return Some(pid);
+        }
+    }
+    None
+}
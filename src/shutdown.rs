@@ -0,0 +1,72 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time to wrap up after SIGTERM or SIGINT before exiting regardless, within the 10s
+/// that `docker stop` allows by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(8);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+thread_local! {
+    /// A shutdown asked for by a test, which only its own thread sees
+    static REQUESTED_HERE: Cell<bool> = const { Cell::new(false) };
+}
+
+extern "C" fn handle(_signal: libc::c_int) {
+    // Only async-signal-safe work here; the watchdog thread does the rest
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Ask for a graceful shutdown on SIGTERM and SIGINT. Modules stop picking up new work
+/// and cancel what they are doing; if that takes longer than `timeout`, the process
+/// exits anyway.
+pub fn install(timeout: Duration) {
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+    thread::Builder::new()
+        .name("shutdown".to_string())
+        .spawn(move || {
+            while !requested() {
+                thread::sleep(Duration::from_millis(200));
+            }
+            info!("Shutting down; exiting in at most {:?}", timeout);
+            thread::sleep(timeout);
+            error!("Did not shut down within {:?}; exiting anyway", timeout);
+            std::process::exit(1);
+        })
+        .expect("failed to start the shutdown thread");
+}
+
+/// True once a shutdown has been asked for
+pub fn requested() -> bool {
+    #[cfg(test)]
+    if REQUESTED_HERE.with(Cell::get) {
+        return true;
+    }
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Ask for a shutdown on this thread only, leaving the tests running alongside alone
+#[cfg(test)]
+pub fn request_here() {
+    REQUESTED_HERE.with(|r| r.set(true));
+}
+
+/// Sleep for `duration`, waking early if a shutdown is asked for
+pub fn sleep(duration: Duration) {
+    let until = Instant::now() + duration;
+    while !requested() {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(Duration::from_millis(200)));
+    }
+}
@@ -0,0 +1,248 @@
+#[cfg(test)]
+pub mod memory;
+mod pg;
+mod sqlite;
+
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// The error of a job that `retry_job` found had stopped updating
+const ABANDONED: &str = "stopped updating; its process died";
+
+/// Whether `path` is beneath the directory `root`
+pub fn in_root(path: &str, root: &str) -> bool {
+    path.starts_with(&format!("{}/", root.trim_end_matches('/')))
+}
+
+/// What scan knows about a file, as stored in `paths`
+#[derive(Clone, Debug)]
+pub struct FileRecord {
+    pub hash: String,
+    pub path: String,
+    pub codec: Option<String>,
+    pub height: Option<i32>,
+    pub width: Option<i32>,
+    pub kbps: Option<f32>,
+    pub extension: Option<String>,
+    pub bytes: i64,
+    pub last_modified: DateTime<Local>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RootRecord {
+    pub root: String,
+    pub active: bool,
+    /// Overrides keyed by service
+    pub config: Value,
+    pub last_scanned: Option<DateTime<Local>>,
+}
+
+/// A row of `reencode_jobs`
+#[derive(Clone, Debug, Default)]
+pub struct JobRecord {
+    pub id: i64,
+    pub path_id: Option<i64>,
+    pub source_path: String,
+    pub output_path: Option<String>,
+    /// running, paused, succeeded, failed, skipped or cancelled
+    pub state: String,
+    pub started_at: DateTime<Local>,
+    pub updated_at: DateTime<Local>,
+    pub finished_at: Option<DateTime<Local>>,
+    /// Seconds of video in the source
+    pub duration: Option<f64>,
+    pub percent: Option<f64>,
+    pub fps: Option<f64>,
+    pub speed: Option<f64>,
+    pub eta_seconds: Option<f64>,
+    pub profile: Option<String>,
+    pub command: Option<String>,
+    pub source_probe: Option<Value>,
+    pub output_probe: Option<Value>,
+    pub source_bytes: Option<i64>,
+    pub output_bytes: Option<i64>,
+    pub bytes_saved: Option<i64>,
+    pub exit_status: Option<String>,
+    pub stderr: Option<String>,
+    pub error: Option<String>,
+    /// vmaf or ssim
+    pub quality_metric: Option<String>,
+    pub quality_score: Option<f64>,
+}
+
+/// What one root wants its files encoded as
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub root: String,
+    pub extension: String,
+    pub codec: String,
+    pub priority: i32,
+    /// Larger files are reencoded even if they already have the codec and extension
+    pub max_height: Option<i32>,
+    pub max_width: Option<i32>,
+    /// Paths whose deepest root is disabled are never queued
+    pub enabled: bool,
+}
+
+/// How paths of equal priority are ordered in the reencode queue
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueueOrder {
+    /// Bigger files have more to gain
+    Savings,
+    Oldest,
+    Newest,
+}
+
+impl QueueOrder {
+    pub fn from_name(name: &str) -> std::result::Result<QueueOrder, String> {
+        match name {
+            "savings" => Ok(QueueOrder::Savings),
+            "oldest" => Ok(QueueOrder::Oldest),
+            "newest" => Ok(QueueOrder::Newest),
+            _ => Err(format!(
+                "unknown order {}; expected savings, oldest or newest",
+                name
+            )),
+        }
+    }
+
+    /// The `ORDER BY` term for a query over `paths p`
+    fn sql(&self) -> &'static str {
+        match self {
+            QueueOrder::Savings => "p.bytes DESC",
+            QueueOrder::Oldest => "p.last_modified ASC",
+            QueueOrder::Newest => "p.last_modified DESC",
+        }
+    }
+}
+
+/// A path waiting to be reencoded
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub id: i64,
+    pub path: String,
+    pub bytes: i64,
+}
+
+/// What the deep health check of scan found in a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthRecord {
+    pub path: String,
+    /// ok or damaged
+    pub status: String,
+    /// What ffmpeg complained about, if anything
+    pub errors: Option<String>,
+    pub checked_at: DateTime<Local>,
+}
+
+/// A known path and, if clean could not find it, since when
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathState {
+    pub path: String,
+    pub missing_since: Option<DateTime<Local>>,
+}
+
+/// Everything the modules keep between runs. Each thread has its own.
+pub trait Store: Send {
+    // Configuration
+    fn service_configs(&mut self) -> Result<Vec<(String, Value)>>;
+    fn set_service_config(&mut self, service: &str, config: &Value) -> Result<()>;
+    /// Wait up to `timeout` for another connection to change the configuration, and
+    /// say whether it did
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<bool>;
+    fn video_extensions(&mut self) -> Result<Vec<String>>;
+    fn set_video_extensions(&mut self, extensions: &[String]) -> Result<()>;
+
+    // Roots
+    fn roots(&mut self) -> Result<Vec<RootRecord>>;
+    /// Add a root, or set whether an existing one is active
+    fn add_root(&mut self, root: &str, active: bool) -> Result<()>;
+    /// False if there was no such root
+    fn remove_root(&mut self, root: &str) -> Result<bool>;
+    /// False if there was no such root
+    fn set_root_config(&mut self, root: &str, config: &Value) -> Result<bool>;
+    fn mark_scanned(&mut self, root: &str) -> Result<()>;
+
+    // Files
+    fn file(&mut self, path: &str) -> Result<Option<FileRecord>>;
+    /// Insert or replace what scan found, marking the file settled and forgetting its
+    /// health
+    fn store_file(&mut self, file: &FileRecord) -> Result<()>;
+    /// Note that scan found a known file unchanged: mark it settled if it wasn't, and no
+    /// longer missing
+    fn mark_settled(&mut self, path: &str) -> Result<()>;
+    /// Keep reencode away from a file until the next scan finds it settled
+    fn mark_unsettled(&mut self, path: &str) -> Result<()>;
+    /// The last health check of a known file, unless it changed since
+    fn health(&mut self, path: &str) -> Result<Option<HealthRecord>>;
+    fn set_health(&mut self, health: &HealthRecord) -> Result<()>;
+    /// The health checks that found damage, by path
+    fn damaged(&mut self) -> Result<Vec<HealthRecord>>;
+    /// Every known file, for reporting
+    fn library(&mut self) -> Result<Vec<FileRecord>>;
+    /// Up to `limit` paths that sort after `after`, in order, to page through them all
+    fn path_states(&mut self, after: Option<&str>, limit: i64) -> Result<Vec<PathState>>;
+    /// Note that these paths couldn't be found, unless they already were missing; returns
+    /// how many were newly marked
+    fn mark_missing(&mut self, paths: &[String]) -> Result<u64>;
+    /// Clear the missing mark of these paths; returns how many had it
+    fn mark_found(&mut self, paths: &[String]) -> Result<u64>;
+    /// Forget these paths; returns how many were known
+    fn delete_paths(&mut self, paths: &[String]) -> Result<u64>;
+
+    // Reencode queue
+    /// Unclaimed, settled, present paths that don't match their root's target yet or are
+    /// larger than it allows, best first: bumped paths, then roots by priority, then
+    /// `order`
+    fn candidates(
+        &mut self,
+        targets: &[Target],
+        order: QueueOrder,
+        limit: i64,
+    ) -> Result<Vec<Candidate>>;
+    /// Claim the first of `candidates` so no one else picks it up
+    fn claim_next(&mut self, targets: &[Target], order: QueueOrder) -> Result<Option<Candidate>>;
+    /// Claim a known path whatever its place in the queue; `None` if it's already claimed
+    fn claim_path(&mut self, path: &str) -> Result<Option<Candidate>>;
+    /// Give up the claim on a path so it goes back in the queue
+    fn release(&mut self, id: i64) -> Result<()>;
+    /// Release a path whose encode was written elsewhere and remember where
+    fn keep_original(&mut self, id: i64, encoded_path: &str) -> Result<()>;
+    /// Set the priority of a path and everything beneath it; returns how many were found
+    fn set_priority(&mut self, path: &str, priority: i32) -> Result<u64>;
+    fn encoded_from(&mut self, path: &str) -> Result<Option<String>>;
+    fn set_encoded_from(&mut self, path: &str, source: &str) -> Result<()>;
+
+    // Jobs
+    /// Insert a job, returning its id
+    fn insert_job(&mut self, job: &JobRecord) -> Result<i64>;
+    fn update_job(&mut self, job: &JobRecord) -> Result<()>;
+    /// Release the path of a finished job, or of a running or paused one that hasn't been
+    /// updated since `stale_before` and is marked failed; false if the job is still
+    /// encoding or has no path
+    fn retry_job(&mut self, id: i64, stale_before: DateTime<Local>) -> Result<bool>;
+    /// The latest job of each claimed path, unless it succeeded: running or stuck
+    fn claimed_jobs(&mut self) -> Result<Vec<JobRecord>>;
+    fn jobs_in_state(&mut self, state: &str) -> Result<Vec<JobRecord>>;
+}
+
+/// Where the data lives, chosen on the command line
+#[derive(Clone, Debug)]
+pub enum Backend {
+    Postgres(Box<postgres::Config>),
+    Sqlite(PathBuf),
+}
+
+impl Backend {
+    pub fn connect(&self) -> Result<Box<dyn Store>> {
+        Ok(match self {
+            Backend::Postgres(config) => Box::new(pg::PostgresStore::connect(config)?),
+            Backend::Sqlite(path) => Box::new(sqlite::SqliteStore::open(path)?),
+        })
+    }
+}
@@ -0,0 +1,596 @@
+use crate::store::sqlite::SqliteStore;
+use crate::store::{
+    in_root, Candidate, FileRecord, HealthRecord, JobRecord, PathState, QueueOrder, Result,
+    RootRecord, Store, Target, ABANDONED,
+};
+use chrono::{DateTime, Local};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use std::{env, fs, process};
+
+/// An empty directory for the files of the test `name`
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("video-processor-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A settled file as scan would store it: 1080p and just modified
+pub fn file_record(path: &str, codec: &str, bytes: i64) -> FileRecord {
+    FileRecord {
+        hash: String::new(),
+        path: path.to_string(),
+        codec: Some(codec.to_string()),
+        height: Some(1080),
+        width: Some(1920),
+        kbps: None,
+        extension: path.rsplit_once('.').map(|(_, e)| e.to_string()),
+        bytes,
+        last_modified: Local::now(),
+    }
+}
+
+/// What tests need of a store beyond `Store`, to set it up and inspect it
+pub trait TestStore: Store {
+    /// memory or sqlite, to keep the scratch files of each apart
+    fn kind(&self) -> &'static str;
+
+    /// Another connection to the same data
+    fn connect(&self) -> Box<dyn TestStore>;
+
+    /// The row of `path`, if it is known
+    fn row(&mut self, path: &str) -> Option<PathRow>;
+
+    /// Add a settled file as scan would, returning its id
+    fn add_file(&mut self, path: &str, codec: &str, bytes: i64) -> i64 {
+        self.store_file(&file_record(path, codec, bytes)).unwrap();
+        self.row(path).unwrap().id
+    }
+}
+
+/// A store in a new SQLite database in the scratch directory `name`
+pub fn sqlite_store(name: &str) -> Box<dyn TestStore> {
+    let path = scratch_dir(&format!("{}-sqlite", name)).join("test.db");
+    Box::new(SqliteStore::open(&path).unwrap())
+}
+
+/// Declares each of `tests`, functions of the tests module taking a `&mut dyn TestStore`,
+/// as a test run against `MemoryStore` in `memory::` and against `SqliteStore` in
+/// `sqlite::`
+macro_rules! backend_tests {
+    ($($test:ident),* $(,)?) => {
+        mod memory {
+            $(
+                #[test]
+                fn $test() {
+                    super::$test(&mut crate::store::memory::MemoryStore::new());
+                }
+            )*
+        }
+
+        mod sqlite {
+            $(
+                #[test]
+                fn $test() {
+                    super::$test(&mut *crate::store::memory::sqlite_store(stringify!($test)));
+                }
+            )*
+        }
+    };
+}
+pub(crate) use backend_tests;
+
+/// A row of `paths`
+#[derive(Clone, Debug)]
+pub struct PathRow {
+    pub id: i64,
+    pub file: FileRecord,
+    pub in_progress: bool,
+    pub priority: i32,
+    pub encoded_path: Option<String>,
+    pub encoded_from: Option<String>,
+    pub settled_at: Option<DateTime<Local>>,
+    pub missing_since: Option<DateTime<Local>>,
+    pub health: Option<HealthRecord>,
+}
+
+#[derive(Debug, Default)]
+pub struct Data {
+    pub services: BTreeMap<String, Value>,
+    pub extensions: Vec<String>,
+    pub roots: Vec<RootRecord>,
+    pub paths: Vec<PathRow>,
+    pub jobs: Vec<JobRecord>,
+    /// Counts changes to `services` and `roots`, for `wait_for_change`
+    version: u64,
+}
+
+impl Data {
+    fn path_mut(&mut self, path: &str) -> Option<&mut PathRow> {
+        self.paths.iter_mut().find(|p| p.file.path == path)
+    }
+
+    fn changed(&mut self) {
+        self.version += 1;
+    }
+}
+
+/// Keeps everything in memory, for tests. Clones share their data, like connections to
+/// the same database.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    data: Arc<Mutex<Data>>,
+    seen: u64,
+}
+
+impl MemoryStore {
+    /// An empty store with the default video extensions
+    pub fn new() -> MemoryStore {
+        let store = MemoryStore::default();
+        store.data().extensions = ["avi", "m2ts", "m4v", "mkv", "mp4", "iso"]
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        store
+    }
+
+    /// Direct access to the data, to set up and inspect tests
+    pub fn data(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().expect("memory store poisoned")
+    }
+
+    fn queue(&self, targets: &[Target], order: QueueOrder) -> Vec<Candidate> {
+        let data = self.data();
+        let mut rows: Vec<(&PathRow, i32)> = Vec::new();
+        for row in data.paths.iter() {
+            let file = &row.file;
+            let extension = match &file.extension {
+                Some(e) if data.extensions.contains(e) => e,
+                _ => continue,
+            };
+            // A path belongs to the deepest root it is in, unless that one is disabled
+            let deepest = targets
+                .iter()
+                .filter(|t| in_root(&file.path, &t.root))
+                .max_by_key(|t| t.root.trim_end_matches('/').len());
+            if let Some(target) = deepest.filter(|t| t.enabled) {
+                let exceeds = |size: Option<i32>, max: Option<i32>| {
+                    size.zip(max).is_some_and(|(size, max)| size > max)
+                };
+                let mismatched = *extension != target.extension
+                    || file.codec.as_ref().is_some_and(|c| *c != target.codec)
+                    || exceeds(file.height, target.max_height)
+                    || exceeds(file.width, target.max_width);
+                if mismatched
+                    && !row.in_progress
+                    && row.encoded_path.is_none()
+                    && row.settled_at.is_some()
+                    && row.missing_since.is_none()
+                {
+                    rows.push((row, target.priority));
+                }
+            }
+        }
+        let by_order = |a: &PathRow, b: &PathRow| -> Ordering {
+            match order {
+                QueueOrder::Savings => b.file.bytes.cmp(&a.file.bytes),
+                QueueOrder::Oldest => a.file.last_modified.cmp(&b.file.last_modified),
+                QueueOrder::Newest => b.file.last_modified.cmp(&a.file.last_modified),
+            }
+        };
+        rows.sort_by(|(a, a_priority), (b, b_priority)| {
+            b.priority
+                .cmp(&a.priority)
+                .then(b_priority.cmp(a_priority))
+                .then(by_order(a, b))
+                .then(a.id.cmp(&b.id))
+        });
+        rows.iter()
+            .map(|(row, _)| Candidate {
+                id: row.id,
+                path: row.file.path.clone(),
+                bytes: row.file.bytes,
+            })
+            .collect()
+    }
+}
+
+impl TestStore for MemoryStore {
+    fn kind(&self) -> &'static str {
+        "memory"
+    }
+
+    fn connect(&self) -> Box<dyn TestStore> {
+        Box::new(self.clone())
+    }
+
+    fn row(&mut self, path: &str) -> Option<PathRow> {
+        self.data()
+            .paths
+            .iter()
+            .find(|p| p.file.path == path)
+            .cloned()
+    }
+}
+
+impl Store for MemoryStore {
+    fn service_configs(&mut self) -> Result<Vec<(String, Value)>> {
+        Ok(self
+            .data()
+            .services
+            .iter()
+            .map(|(s, c)| (s.clone(), c.clone()))
+            .collect())
+    }
+
+    fn set_service_config(&mut self, service: &str, config: &Value) -> Result<()> {
+        let mut data = self.data();
+        data.services.insert(service.to_string(), config.clone());
+        data.changed();
+        Ok(())
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<bool> {
+        let version = self.data().version;
+        if version == self.seen {
+            thread::sleep(timeout);
+        }
+        let version = self.data().version;
+        let changed = version != self.seen;
+        self.seen = version;
+        Ok(changed)
+    }
+
+    fn video_extensions(&mut self) -> Result<Vec<String>> {
+        let mut extensions = self.data().extensions.clone();
+        extensions.sort();
+        Ok(extensions)
+    }
+
+    fn set_video_extensions(&mut self, extensions: &[String]) -> Result<()> {
+        self.data().extensions = extensions.to_vec();
+        Ok(())
+    }
+
+    fn roots(&mut self) -> Result<Vec<RootRecord>> {
+        Ok(self.data().roots.clone())
+    }
+
+    fn add_root(&mut self, root: &str, active: bool) -> Result<()> {
+        let mut data = self.data();
+        match data.roots.iter_mut().find(|r| r.root == root) {
+            Some(existing) if existing.active == active => return Ok(()),
+            Some(existing) => existing.active = active,
+            None => {
+                data.roots.push(RootRecord {
+                    root: root.to_string(),
+                    active,
+                    config: json!({}),
+                    last_scanned: None,
+                });
+                data.roots.sort_by(|a, b| a.root.cmp(&b.root));
+            }
+        }
+        data.changed();
+        Ok(())
+    }
+
+    fn remove_root(&mut self, root: &str) -> Result<bool> {
+        let mut data = self.data();
+        let before = data.roots.len();
+        data.roots.retain(|r| r.root != root);
+        let removed = data.roots.len() < before;
+        if removed {
+            data.changed();
+        }
+        Ok(removed)
+    }
+
+    fn set_root_config(&mut self, root: &str, config: &Value) -> Result<bool> {
+        let mut data = self.data();
+        let found = match data.roots.iter_mut().find(|r| r.root == root) {
+            Some(existing) => {
+                existing.config = config.clone();
+                true
+            }
+            None => false,
+        };
+        if found {
+            data.changed();
+        }
+        Ok(found)
+    }
+
+    fn mark_scanned(&mut self, root: &str) -> Result<()> {
+        if let Some(existing) = self.data().roots.iter_mut().find(|r| r.root == root) {
+            existing.last_scanned = Some(Local::now());
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str) -> Result<Option<FileRecord>> {
+        Ok(self
+            .data()
+            .paths
+            .iter()
+            .find(|p| p.file.path == path)
+            .map(|row| row.file.clone()))
+    }
+
+    fn store_file(&mut self, file: &FileRecord) -> Result<()> {
+        let mut data = self.data();
+        match data.path_mut(&file.path) {
+            Some(row) => {
+                row.file = file.clone();
+                row.settled_at = Some(Local::now());
+                row.missing_since = None;
+                row.health = None;
+            }
+            None => {
+                let id = data.paths.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+                data.paths.push(PathRow {
+                    id,
+                    file: file.clone(),
+                    in_progress: false,
+                    priority: 0,
+                    encoded_path: None,
+                    encoded_from: None,
+                    settled_at: Some(Local::now()),
+                    missing_since: None,
+                    health: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn mark_settled(&mut self, path: &str) -> Result<()> {
+        if let Some(row) = self.data().path_mut(path) {
+            row.settled_at.get_or_insert_with(Local::now);
+            row.missing_since = None;
+        }
+        Ok(())
+    }
+
+    fn mark_unsettled(&mut self, path: &str) -> Result<()> {
+        if let Some(row) = self.data().path_mut(path) {
+            row.settled_at = None;
+        }
+        Ok(())
+    }
+
+    fn health(&mut self, path: &str) -> Result<Option<HealthRecord>> {
+        Ok(self.row(path).and_then(|row| row.health))
+    }
+
+    fn set_health(&mut self, health: &HealthRecord) -> Result<()> {
+        if let Some(row) = self.data().path_mut(&health.path) {
+            row.health = Some(health.clone());
+        }
+        Ok(())
+    }
+
+    fn damaged(&mut self) -> Result<Vec<HealthRecord>> {
+        let mut damaged: Vec<HealthRecord> = self
+            .data()
+            .paths
+            .iter()
+            .filter_map(|row| row.health.clone())
+            .filter(|health| health.status == "damaged")
+            .collect();
+        damaged.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(damaged)
+    }
+
+    fn library(&mut self) -> Result<Vec<FileRecord>> {
+        let mut rows: Vec<FileRecord> = self
+            .data()
+            .paths
+            .iter()
+            .map(|row| row.file.clone())
+            .collect();
+        rows.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(rows)
+    }
+
+    fn path_states(&mut self, after: Option<&str>, limit: i64) -> Result<Vec<PathState>> {
+        let mut paths: Vec<PathState> = self
+            .data()
+            .paths
+            .iter()
+            .filter(|row| after.is_none_or(|after| row.file.path.as_str() > after))
+            .map(|row| PathState {
+                path: row.file.path.clone(),
+                missing_since: row.missing_since,
+            })
+            .collect();
+        paths.sort_by(|a, b| a.path.cmp(&b.path));
+        paths.truncate(limit as usize);
+        Ok(paths)
+    }
+
+    fn mark_missing(&mut self, paths: &[String]) -> Result<u64> {
+        let mut marked = 0;
+        for row in self.data().paths.iter_mut() {
+            if paths.contains(&row.file.path) && row.missing_since.is_none() {
+                row.missing_since = Some(Local::now());
+                marked += 1;
+            }
+        }
+        Ok(marked)
+    }
+
+    fn mark_found(&mut self, paths: &[String]) -> Result<u64> {
+        let mut found = 0;
+        for row in self.data().paths.iter_mut() {
+            if paths.contains(&row.file.path) && row.missing_since.take().is_some() {
+                found += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    fn delete_paths(&mut self, paths: &[String]) -> Result<u64> {
+        let mut data = self.data();
+        let ids: Vec<i64> = data
+            .paths
+            .iter()
+            .filter(|row| paths.contains(&row.file.path))
+            .map(|row| row.id)
+            .collect();
+        data.paths.retain(|row| !ids.contains(&row.id));
+        for job in data.jobs.iter_mut() {
+            if job.path_id.is_some_and(|id| ids.contains(&id)) {
+                job.path_id = None;
+            }
+        }
+        Ok(ids.len() as u64)
+    }
+
+    fn candidates(
+        &mut self,
+        targets: &[Target],
+        order: QueueOrder,
+        limit: i64,
+    ) -> Result<Vec<Candidate>> {
+        let mut queue = self.queue(targets, order);
+        queue.truncate(limit as usize);
+        Ok(queue)
+    }
+
+    fn claim_next(&mut self, targets: &[Target], order: QueueOrder) -> Result<Option<Candidate>> {
+        let next = self.queue(targets, order).into_iter().next();
+        if let Some(next) = &next {
+            self.data().path_mut(&next.path).unwrap().in_progress = true;
+        }
+        Ok(next)
+    }
+
+    fn claim_path(&mut self, path: &str) -> Result<Option<Candidate>> {
+        Ok(match self.data().path_mut(path) {
+            Some(row) if !row.in_progress => {
+                row.in_progress = true;
+                Some(Candidate {
+                    id: row.id,
+                    path: row.file.path.clone(),
+                    bytes: row.file.bytes,
+                })
+            }
+            _ => None,
+        })
+    }
+
+    fn release(&mut self, id: i64) -> Result<()> {
+        if let Some(row) = self.data().paths.iter_mut().find(|p| p.id == id) {
+            row.in_progress = false;
+        }
+        Ok(())
+    }
+
+    fn keep_original(&mut self, id: i64, encoded_path: &str) -> Result<()> {
+        if let Some(row) = self.data().paths.iter_mut().find(|p| p.id == id) {
+            row.in_progress = false;
+            row.encoded_path = Some(encoded_path.to_string());
+        }
+        Ok(())
+    }
+
+    fn set_priority(&mut self, path: &str, priority: i32) -> Result<u64> {
+        let mut updated = 0;
+        for row in self.data().paths.iter_mut() {
+            if row.file.path == path || in_root(&row.file.path, path) {
+                row.priority = priority;
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    }
+
+    fn encoded_from(&mut self, path: &str) -> Result<Option<String>> {
+        Ok(self.row(path).and_then(|row| row.encoded_from))
+    }
+
+    fn set_encoded_from(&mut self, path: &str, source: &str) -> Result<()> {
+        if let Some(row) = self.data().path_mut(path) {
+            row.encoded_from = Some(source.to_string());
+        }
+        Ok(())
+    }
+
+    fn insert_job(&mut self, job: &JobRecord) -> Result<i64> {
+        let mut data = self.data();
+        let id = data.jobs.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        data.jobs.push(JobRecord { id, ..job.clone() });
+        Ok(id)
+    }
+
+    fn update_job(&mut self, job: &JobRecord) -> Result<()> {
+        if let Some(existing) = self.data().jobs.iter_mut().find(|j| j.id == job.id) {
+            *existing = job.clone();
+        }
+        Ok(())
+    }
+
+    fn retry_job(&mut self, id: i64, stale_before: DateTime<Local>) -> Result<bool> {
+        let mut data = self.data();
+        let job = data.jobs.iter_mut().find(|j| j.id == id);
+        let path_id = match job {
+            Some(job) if job.state != "running" && job.state != "paused" => job.path_id,
+            Some(job) if job.updated_at < stale_before => {
+                job.state = "failed".to_string();
+                job.error = Some(ABANDONED.to_string());
+                job.finished_at = Some(Local::now());
+                job.eta_seconds = None;
+                job.path_id
+            }
+            _ => None,
+        };
+        Ok(
+            match data.paths.iter_mut().find(|p| Some(p.id) == path_id) {
+                Some(row) => {
+                    row.in_progress = false;
+                    true
+                }
+                None => false,
+            },
+        )
+    }
+
+    fn claimed_jobs(&mut self) -> Result<Vec<JobRecord>> {
+        let data = self.data();
+        Ok(data
+            .jobs
+            .iter()
+            .filter(|job| {
+                let latest = data
+                    .jobs
+                    .iter()
+                    .filter(|j| j.path_id.is_some() && j.path_id == job.path_id)
+                    .map(|j| j.id)
+                    .max();
+                let claimed = data
+                    .paths
+                    .iter()
+                    .any(|p| Some(p.id) == job.path_id && p.in_progress);
+                job.state != "succeeded" && latest == Some(job.id) && claimed
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn jobs_in_state(&mut self, state: &str) -> Result<Vec<JobRecord>> {
+        Ok(self
+            .data()
+            .jobs
+            .iter()
+            .filter(|j| j.state == state)
+            .cloned()
+            .collect())
+    }
+}
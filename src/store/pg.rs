@@ -0,0 +1,599 @@
+use crate::store::{
+    Candidate, FileRecord, HealthRecord, JobRecord, PathState, QueueOrder, Result, RootRecord,
+    Store, Target, ABANDONED,
+};
+use chrono::{DateTime, Local};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::row::Row;
+use postgres::{Client, NoTls};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Channel the schema's triggers notify when `config` or `roots` change
+const CHANNEL: &str = "config_changed";
+
+const FILE_COLUMNS: &str =
+    "hash, path, codec, height, width, kbps, extension, bytes, last_modified";
+
+const JOB_COLUMNS: &str = "id, path_id, source_path, output_path, state, started_at, \
+    updated_at, finished_at, duration, percent, fps, speed, eta_seconds, profile, command, \
+    source_probe, output_probe, source_bytes, output_bytes, bytes_saved, exit_status, \
+    stderr, error, quality_metric, quality_score";
+
+fn file_from_row(row: &Row) -> FileRecord {
+    FileRecord {
+        hash: row.get("hash"),
+        path: row.get("path"),
+        codec: row.get("codec"),
+        height: row.get("height"),
+        width: row.get("width"),
+        kbps: row.get("kbps"),
+        extension: row.get("extension"),
+        bytes: row.get("bytes"),
+        last_modified: row.get("last_modified"),
+    }
+}
+
+fn job_from_row(row: &Row) -> JobRecord {
+    JobRecord {
+        id: row.get("id"),
+        path_id: row.get("path_id"),
+        source_path: row.get("source_path"),
+        output_path: row.get("output_path"),
+        state: row.get("state"),
+        started_at: row.get("started_at"),
+        updated_at: row.get("updated_at"),
+        finished_at: row.get("finished_at"),
+        duration: row.get("duration"),
+        percent: row.get("percent"),
+        fps: row.get("fps"),
+        speed: row.get("speed"),
+        eta_seconds: row.get("eta_seconds"),
+        profile: row.get("profile"),
+        command: row.get("command"),
+        source_probe: row.get("source_probe"),
+        output_probe: row.get("output_probe"),
+        source_bytes: row.get("source_bytes"),
+        output_bytes: row.get("output_bytes"),
+        bytes_saved: row.get("bytes_saved"),
+        exit_status: row.get("exit_status"),
+        stderr: row.get("stderr"),
+        error: row.get("error"),
+        quality_metric: row.get("quality_metric"),
+        quality_score: row.get("quality_score"),
+    }
+}
+
+fn health_from_row(row: &Row) -> HealthRecord {
+    HealthRecord {
+        path: row.get(0),
+        status: row.get(1),
+        errors: row.get(2),
+        checked_at: row.get(3),
+    }
+}
+
+fn candidate_from_row(row: &Row) -> Candidate {
+    Candidate {
+        id: row.get(0),
+        path: row.get(1),
+        bytes: row.get(2),
+    }
+}
+
+/// Paths that don't match their root's target yet, best candidates first. The targets
+/// are passed as arrays in $1 to $7. A path belongs to the deepest root it is in, and is
+/// left alone if that root is disabled.
+fn candidates_query(order: QueueOrder) -> String {
+    format!(
+        "SELECT p.id, p.path, p.bytes FROM paths p \
+            INNER JOIN video_extensions USING(extension) \
+            INNER JOIN unnest($1::text[], $2::text[], $3::text[], $4::int[], $5::int[], \
+                    $6::int[], $7::bool[]) \
+                AS t(root, target_extension, target_codec, priority, max_height, max_width, \
+                    enabled) \
+                ON starts_with(p.path, rtrim(t.root, '/') || '/') \
+                AND NOT EXISTS (SELECT 1 FROM unnest($1::text[]) AS d(root) \
+                    WHERE starts_with(p.path, rtrim(d.root, '/') || '/') \
+                        AND length(rtrim(d.root, '/')) > length(rtrim(t.root, '/'))) \
+            WHERE t.enabled AND (p.extension != t.target_extension \
+                    OR p.codec != t.target_codec OR p.height > t.max_height \
+                    OR p.width > t.max_width) \
+                AND NOT p.in_progress AND p.encoded_path IS NULL AND p.settled_at IS NOT NULL \
+                AND p.missing_since IS NULL \
+            ORDER BY p.priority DESC, t.priority DESC, {}, p.id",
+        order.sql()
+    )
+}
+
+/// Query parameters describing each root's target, for `candidates_query`
+struct Targets<'a> {
+    roots: Vec<&'a str>,
+    extensions: Vec<&'a str>,
+    codecs: Vec<&'a str>,
+    priorities: Vec<i32>,
+    max_heights: Vec<Option<i32>>,
+    max_widths: Vec<Option<i32>>,
+    enabled: Vec<bool>,
+}
+
+impl<'a> Targets<'a> {
+    fn new(targets: &'a [Target]) -> Targets<'a> {
+        Targets {
+            roots: targets.iter().map(|t| t.root.as_str()).collect(),
+            extensions: targets.iter().map(|t| t.extension.as_str()).collect(),
+            codecs: targets.iter().map(|t| t.codec.as_str()).collect(),
+            priorities: targets.iter().map(|t| t.priority).collect(),
+            max_heights: targets.iter().map(|t| t.max_height).collect(),
+            max_widths: targets.iter().map(|t| t.max_width).collect(),
+            enabled: targets.iter().map(|t| t.enabled).collect(),
+        }
+    }
+}
+
+pub struct PostgresStore {
+    client: Client,
+    listening: bool,
+}
+
+impl PostgresStore {
+    pub fn connect(config: &postgres::Config) -> Result<PostgresStore> {
+        Ok(PostgresStore {
+            client: config.connect(NoTls)?,
+            listening: false,
+        })
+    }
+}
+
+impl Store for PostgresStore {
+    fn service_configs(&mut self) -> Result<Vec<(String, Value)>> {
+        Ok(self
+            .client
+            .query("SELECT service, config FROM config", &[])?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+
+    fn set_service_config(&mut self, service: &str, config: &Value) -> Result<()> {
+        self.client.execute(
+            "INSERT INTO config (service, config) VALUES ($1, $2) \
+                ON CONFLICT (service) DO UPDATE SET config = EXCLUDED.config",
+            &[&service, &config],
+        )?;
+        Ok(())
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<bool> {
+        if !self.listening {
+            self.client.batch_execute(&format!("LISTEN {}", CHANNEL))?;
+            self.listening = true;
+        }
+        let notified = self
+            .client
+            .notifications()
+            .timeout_iter(timeout)
+            .next()?
+            .is_some();
+        if notified {
+            // Collapse a burst of changes into one
+            while self.client.notifications().iter().next()?.is_some() {}
+        }
+        Ok(notified)
+    }
+
+    fn video_extensions(&mut self) -> Result<Vec<String>> {
+        Ok(self
+            .client
+            .query(
+                "SELECT extension FROM video_extensions ORDER BY extension",
+                &[],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn set_video_extensions(&mut self, extensions: &[String]) -> Result<()> {
+        let mut transaction = self.client.transaction()?;
+        transaction.execute(
+            "INSERT INTO video_extensions (extension) SELECT unnest($1::text[]) \
+                ON CONFLICT DO NOTHING",
+            &[&extensions],
+        )?;
+        transaction.execute(
+            "DELETE FROM video_extensions WHERE NOT extension = ANY($1)",
+            &[&extensions],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn roots(&mut self) -> Result<Vec<RootRecord>> {
+        Ok(self
+            .client
+            .query(
+                "SELECT root, active, config, last_scanned FROM roots ORDER BY root",
+                &[],
+            )?
+            .iter()
+            .map(|row| RootRecord {
+                root: row.get(0),
+                active: row.get(1),
+                config: row.get(2),
+                last_scanned: row.get(3),
+            })
+            .collect())
+    }
+
+    fn add_root(&mut self, root: &str, active: bool) -> Result<()> {
+        self.client.execute(
+            "INSERT INTO roots (root, active) VALUES ($1, $2) \
+                ON CONFLICT (root) DO UPDATE SET active = EXCLUDED.active \
+                WHERE roots.active != EXCLUDED.active",
+            &[&root, &active],
+        )?;
+        Ok(())
+    }
+
+    fn remove_root(&mut self, root: &str) -> Result<bool> {
+        Ok(self
+            .client
+            .execute("DELETE FROM roots WHERE root = $1", &[&root])?
+            > 0)
+    }
+
+    fn set_root_config(&mut self, root: &str, config: &Value) -> Result<bool> {
+        Ok(self.client.execute(
+            "UPDATE roots SET config = $2 WHERE root = $1",
+            &[&root, &config],
+        )? > 0)
+    }
+
+    fn mark_scanned(&mut self, root: &str) -> Result<()> {
+        self.client.execute(
+            "UPDATE roots SET last_scanned = now() WHERE root = $1",
+            &[&root],
+        )?;
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str) -> Result<Option<FileRecord>> {
+        let query = format!("SELECT {} FROM paths WHERE path = $1", FILE_COLUMNS);
+        Ok(self
+            .client
+            .query(query.as_str(), &[&path])?
+            .first()
+            .map(file_from_row))
+    }
+
+    fn store_file(&mut self, file: &FileRecord) -> Result<()> {
+        self.client.execute(
+            "INSERT INTO paths (hash, path, codec, height, width, kbps, extension, bytes, \
+                last_modified, settled_at) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now()) \
+                ON CONFLICT (path) DO UPDATE SET (hash, codec, height, width, kbps, extension, \
+                    bytes, last_modified, settled_at, missing_since, health, health_errors, \
+                    health_checked_at) = (EXCLUDED.hash, EXCLUDED.codec, EXCLUDED.height, \
+                    EXCLUDED.width, EXCLUDED.kbps, EXCLUDED.extension, EXCLUDED.bytes, \
+                    EXCLUDED.last_modified, now(), NULL, NULL, NULL, NULL)",
+            &[
+                &file.hash,
+                &file.path,
+                &file.codec,
+                &file.height,
+                &file.width,
+                &file.kbps,
+                &file.extension,
+                &file.bytes,
+                &file.last_modified,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn mark_settled(&mut self, path: &str) -> Result<()> {
+        self.client.execute(
+            "UPDATE paths SET settled_at = coalesce(settled_at, now()), missing_since = NULL \
+                WHERE path = $1 AND (settled_at IS NULL OR missing_since IS NOT NULL)",
+            &[&path],
+        )?;
+        Ok(())
+    }
+
+    fn mark_unsettled(&mut self, path: &str) -> Result<()> {
+        self.client.execute(
+            "UPDATE paths SET settled_at = NULL WHERE path = $1",
+            &[&path],
+        )?;
+        Ok(())
+    }
+
+    fn health(&mut self, path: &str) -> Result<Option<HealthRecord>> {
+        Ok(self
+            .client
+            .query(
+                "SELECT path, health, health_errors, health_checked_at FROM paths \
+                    WHERE path = $1 AND health IS NOT NULL",
+                &[&path],
+            )?
+            .first()
+            .map(health_from_row))
+    }
+
+    fn set_health(&mut self, health: &HealthRecord) -> Result<()> {
+        self.client.execute(
+            "UPDATE paths SET (health, health_errors, health_checked_at) = ($2, $3, $4) \
+                WHERE path = $1",
+            &[
+                &health.path,
+                &health.status,
+                &health.errors,
+                &health.checked_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn damaged(&mut self) -> Result<Vec<HealthRecord>> {
+        Ok(self
+            .client
+            .query(
+                "SELECT path, health, health_errors, health_checked_at FROM paths \
+                    WHERE health = 'damaged' ORDER BY path",
+                &[],
+            )?
+            .iter()
+            .map(health_from_row)
+            .collect())
+    }
+
+    fn library(&mut self) -> Result<Vec<FileRecord>> {
+        let query = format!("SELECT {} FROM paths ORDER BY path", FILE_COLUMNS);
+        Ok(self
+            .client
+            .query(query.as_str(), &[])?
+            .iter()
+            .map(file_from_row)
+            .collect())
+    }
+
+    fn path_states(&mut self, after: Option<&str>, limit: i64) -> Result<Vec<PathState>> {
+        Ok(self
+            .client
+            .query(
+                "SELECT path, missing_since FROM paths WHERE $1::text IS NULL OR path > $1 \
+                    ORDER BY path LIMIT $2",
+                &[&after, &limit],
+            )?
+            .iter()
+            .map(|row| PathState {
+                path: row.get(0),
+                missing_since: row.get(1),
+            })
+            .collect())
+    }
+
+    fn mark_missing(&mut self, paths: &[String]) -> Result<u64> {
+        Ok(self.client.execute(
+            "UPDATE paths SET missing_since = now() \
+                WHERE path = ANY($1) AND missing_since IS NULL",
+            &[&paths],
+        )?)
+    }
+
+    fn mark_found(&mut self, paths: &[String]) -> Result<u64> {
+        Ok(self.client.execute(
+            "UPDATE paths SET missing_since = NULL \
+                WHERE path = ANY($1) AND missing_since IS NOT NULL",
+            &[&paths],
+        )?)
+    }
+
+    fn delete_paths(&mut self, paths: &[String]) -> Result<u64> {
+        Ok(self
+            .client
+            .execute("DELETE FROM paths WHERE path = ANY($1)", &[&paths])?)
+    }
+
+    fn candidates(
+        &mut self,
+        targets: &[Target],
+        order: QueueOrder,
+        limit: i64,
+    ) -> Result<Vec<Candidate>> {
+        let query = format!("{} LIMIT $8", candidates_query(order));
+        let t = Targets::new(targets);
+        Ok(self
+            .client
+            .query(
+                query.as_str(),
+                &[
+                    &t.roots,
+                    &t.extensions,
+                    &t.codecs,
+                    &t.priorities,
+                    &t.max_heights,
+                    &t.max_widths,
+                    &t.enabled,
+                    &limit,
+                ],
+            )?
+            .iter()
+            .map(candidate_from_row)
+            .collect())
+    }
+
+    fn claim_next(&mut self, targets: &[Target], order: QueueOrder) -> Result<Option<Candidate>> {
+        // Workers skip a row another one is claiming rather than wait and come up empty
+        let query = format!(
+            "UPDATE paths SET in_progress = true \
+                WHERE id = (SELECT id FROM ({} LIMIT 1 FOR UPDATE OF p SKIP LOCKED) c) \
+                    AND NOT in_progress \
+                RETURNING id, path, bytes",
+            candidates_query(order)
+        );
+        let t = Targets::new(targets);
+        Ok(self
+            .client
+            .query(
+                query.as_str(),
+                &[
+                    &t.roots,
+                    &t.extensions,
+                    &t.codecs,
+                    &t.priorities,
+                    &t.max_heights,
+                    &t.max_widths,
+                    &t.enabled,
+                ],
+            )?
+            .first()
+            .map(candidate_from_row))
+    }
+
+    fn claim_path(&mut self, path: &str) -> Result<Option<Candidate>> {
+        Ok(self
+            .client
+            .query(
+                "UPDATE paths SET in_progress = true WHERE path = $1 AND NOT in_progress \
+                    RETURNING id, path, bytes",
+                &[&path],
+            )?
+            .first()
+            .map(candidate_from_row))
+    }
+
+    fn release(&mut self, id: i64) -> Result<()> {
+        self.client
+            .execute("UPDATE paths SET in_progress = false WHERE id = $1", &[&id])?;
+        Ok(())
+    }
+
+    fn keep_original(&mut self, id: i64, encoded_path: &str) -> Result<()> {
+        self.client.execute(
+            "UPDATE paths SET in_progress = false, encoded_path = $2 WHERE id = $1",
+            &[&id, &encoded_path],
+        )?;
+        Ok(())
+    }
+
+    fn set_priority(&mut self, path: &str, priority: i32) -> Result<u64> {
+        Ok(self.client.execute(
+            "UPDATE paths SET priority = $2 WHERE path = $1 OR starts_with(path, $1 || '/')",
+            &[&path, &priority],
+        )?)
+    }
+
+    fn encoded_from(&mut self, path: &str) -> Result<Option<String>> {
+        Ok(self
+            .client
+            .query("SELECT encoded_from FROM paths WHERE path = $1", &[&path])?
+            .first()
+            .and_then(|row| row.get(0)))
+    }
+
+    fn set_encoded_from(&mut self, path: &str, source: &str) -> Result<()> {
+        self.client.execute(
+            "UPDATE paths SET encoded_from = $2 WHERE path = $1",
+            &[&path, &source],
+        )?;
+        Ok(())
+    }
+
+    fn insert_job(&mut self, job: &JobRecord) -> Result<i64> {
+        Ok(self
+            .client
+            .query_one(
+                "INSERT INTO reencode_jobs (path_id, source_path, state) VALUES ($1, $2, $3) \
+                    RETURNING id",
+                &[&job.path_id, &job.source_path, &job.state],
+            )?
+            .get(0))
+    }
+
+    fn update_job(&mut self, job: &JobRecord) -> Result<()> {
+        self.client.execute(
+            "UPDATE reencode_jobs SET path_id = $2, source_path = $3, output_path = $4, \
+                state = $5, updated_at = now(), finished_at = $6, duration = $7, percent = $8, \
+                fps = $9, speed = $10, eta_seconds = $11, profile = $12, command = $13, \
+                source_probe = $14, output_probe = $15, source_bytes = $16, \
+                output_bytes = $17, bytes_saved = $18, exit_status = $19, stderr = $20, \
+                error = $21, quality_metric = $22, quality_score = $23 \
+                WHERE id = $1",
+            &[
+                &job.id,
+                &job.path_id,
+                &job.source_path,
+                &job.output_path,
+                &job.state,
+                &job.finished_at,
+                &job.duration,
+                &job.percent,
+                &job.fps,
+                &job.speed,
+                &job.eta_seconds,
+                &job.profile,
+                &job.command,
+                &job.source_probe,
+                &job.output_probe,
+                &job.source_bytes,
+                &job.output_bytes,
+                &job.bytes_saved,
+                &job.exit_status,
+                &job.stderr,
+                &job.error,
+                &job.quality_metric,
+                &job.quality_score,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn retry_job(&mut self, id: i64, stale_before: DateTime<Local>) -> Result<bool> {
+        let mut transaction = self.client.transaction()?;
+        let released = transaction.execute(
+            "UPDATE paths SET in_progress = false WHERE id = ( \
+                SELECT path_id FROM reencode_jobs WHERE id = $1 \
+                    AND (state NOT IN ('running', 'paused') OR updated_at < $2))",
+            &[&id, &stale_before],
+        )?;
+        transaction.execute(
+            "UPDATE reencode_jobs SET state = 'failed', error = $3, finished_at = now(), \
+                eta_seconds = NULL \
+                WHERE id = $1 AND state IN ('running', 'paused') AND updated_at < $2",
+            &[&id, &stale_before, &ABANDONED],
+        )?;
+        transaction.commit()?;
+        Ok(released > 0)
+    }
+
+    fn claimed_jobs(&mut self) -> Result<Vec<JobRecord>> {
+        let query = format!(
+            "SELECT {} FROM reencode_jobs j WHERE j.state != 'succeeded' \
+                AND j.id = (SELECT max(id) FROM reencode_jobs l WHERE l.path_id = j.path_id) \
+                AND j.path_id IN (SELECT id FROM paths WHERE in_progress) \
+                ORDER BY j.id",
+            JOB_COLUMNS
+        );
+        Ok(self
+            .client
+            .query(query.as_str(), &[])?
+            .iter()
+            .map(job_from_row)
+            .collect())
+    }
+
+    fn jobs_in_state(&mut self, state: &str) -> Result<Vec<JobRecord>> {
+        let query = format!(
+            "SELECT {} FROM reencode_jobs WHERE state = $1 ORDER BY id",
+            JOB_COLUMNS
+        );
+        Ok(self
+            .client
+            .query(query.as_str(), &[&state])?
+            .iter()
+            .map(job_from_row)
+            .collect())
+    }
+}
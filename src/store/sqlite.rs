@@ -0,0 +1,628 @@
+use crate::store::{
+    Candidate, FileRecord, HealthRecord, JobRecord, PathState, QueueOrder, Result, RootRecord,
+    Store, Target, ABANDONED,
+};
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How long a connection waits for another one's write to finish
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const FILE_COLUMNS: &str =
+    "hash, path, codec, height, width, kbps, extension, bytes, last_modified";
+
+const JOB_COLUMNS: &str = "id, path_id, source_path, output_path, state, started_at, \
+    updated_at, finished_at, duration, percent, fps, speed, eta_seconds, profile, command, \
+    source_probe, output_probe, source_bytes, output_bytes, bytes_saved, exit_status, \
+    stderr, error, quality_metric, quality_score";
+
+/// Timestamps are stored as UTC text, so they sort the same as they compare
+fn utc(time: &DateTime<Local>) -> DateTime<Utc> {
+    time.with_timezone(&Utc)
+}
+
+fn now() -> DateTime<Utc> {
+    Utc::now()
+}
+
+/// JSON columns are stored as text
+fn json_text(value: &Value) -> String {
+    value.to_string()
+}
+
+fn parse_json(text: String) -> rusqlite::Result<Value> {
+    serde_json::from_str(&text).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn file_from_row(row: &Row) -> rusqlite::Result<FileRecord> {
+    Ok(FileRecord {
+        hash: row.get("hash")?,
+        path: row.get("path")?,
+        codec: row.get("codec")?,
+        height: row.get("height")?,
+        width: row.get("width")?,
+        kbps: row.get("kbps")?,
+        extension: row.get("extension")?,
+        bytes: row.get("bytes")?,
+        last_modified: row.get("last_modified")?,
+    })
+}
+
+fn job_from_row(row: &Row) -> rusqlite::Result<JobRecord> {
+    Ok(JobRecord {
+        id: row.get("id")?,
+        path_id: row.get("path_id")?,
+        source_path: row.get("source_path")?,
+        output_path: row.get("output_path")?,
+        state: row.get("state")?,
+        started_at: row.get("started_at")?,
+        updated_at: row.get("updated_at")?,
+        finished_at: row.get("finished_at")?,
+        duration: row.get("duration")?,
+        percent: row.get("percent")?,
+        fps: row.get("fps")?,
+        speed: row.get("speed")?,
+        eta_seconds: row.get("eta_seconds")?,
+        profile: row.get("profile")?,
+        command: row.get("command")?,
+        source_probe: row
+            .get::<_, Option<String>>("source_probe")?
+            .map(parse_json)
+            .transpose()?,
+        output_probe: row
+            .get::<_, Option<String>>("output_probe")?
+            .map(parse_json)
+            .transpose()?,
+        source_bytes: row.get("source_bytes")?,
+        output_bytes: row.get("output_bytes")?,
+        bytes_saved: row.get("bytes_saved")?,
+        exit_status: row.get("exit_status")?,
+        stderr: row.get("stderr")?,
+        error: row.get("error")?,
+        quality_metric: row.get("quality_metric")?,
+        quality_score: row.get("quality_score")?,
+    })
+}
+
+fn health_from_row(row: &Row) -> rusqlite::Result<HealthRecord> {
+    Ok(HealthRecord {
+        path: row.get(0)?,
+        status: row.get(1)?,
+        errors: row.get(2)?,
+        checked_at: row.get(3)?,
+    })
+}
+
+fn candidate_from_row(row: &Row) -> rusqlite::Result<Candidate> {
+    Ok(Candidate {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        bytes: row.get(2)?,
+    })
+}
+
+/// As in Postgres, with the targets passed as a JSON array in ?1
+fn candidates_query(order: QueueOrder) -> String {
+    format!(
+        "SELECT p.id, p.path, p.bytes FROM paths p \
+            INNER JOIN video_extensions v ON v.extension = p.extension \
+            INNER JOIN (SELECT json_extract(value, '$.root') AS root, \
+                    json_extract(value, '$.extension') AS target_extension, \
+                    json_extract(value, '$.codec') AS target_codec, \
+                    json_extract(value, '$.priority') AS priority, \
+                    json_extract(value, '$.max_height') AS max_height, \
+                    json_extract(value, '$.max_width') AS max_width, \
+                    json_extract(value, '$.enabled') AS enabled \
+                FROM json_each(?1)) t \
+                ON substr(p.path, 1, length(rtrim(t.root, '/')) + 1) = rtrim(t.root, '/') || '/' \
+                AND NOT EXISTS (SELECT 1 FROM (SELECT rtrim(json_extract(value, '$.root'), '/') \
+                        AS root FROM json_each(?1)) d \
+                    WHERE substr(p.path, 1, length(d.root) + 1) = d.root || '/' \
+                        AND length(d.root) > length(rtrim(t.root, '/'))) \
+            WHERE t.enabled AND (p.extension != t.target_extension \
+                    OR p.codec != t.target_codec OR p.height > t.max_height \
+                    OR p.width > t.max_width) \
+                AND NOT p.in_progress AND p.encoded_path IS NULL AND p.settled_at IS NOT NULL \
+                AND p.missing_since IS NULL \
+            ORDER BY p.priority DESC, t.priority DESC, {}, p.id",
+        order.sql()
+    )
+}
+
+fn targets_json(targets: &[Target]) -> String {
+    Value::Array(
+        targets
+            .iter()
+            .map(|t| {
+                json!({
+                    "root": t.root,
+                    "extension": t.extension,
+                    "codec": t.codec,
+                    "priority": t.priority,
+                    "max_height": t.max_height,
+                    "max_width": t.max_width,
+                    "enabled": t.enabled,
+                })
+            })
+            .collect(),
+    )
+    .to_string()
+}
+
+/// The configuration as last seen by `wait_for_change`
+type Snapshot = (Vec<(String, Value)>, Vec<(String, bool, Value)>);
+
+pub struct SqliteStore {
+    conn: Connection,
+    seen: Option<Snapshot>,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it if need be
+    pub fn open(path: &Path) -> Result<SqliteStore> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.execute_batch(include_str!("../../schema-sqlite.sql"))?;
+        Ok(SqliteStore { conn, seen: None })
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        let roots = self
+            .roots()?
+            .into_iter()
+            .map(|r| (r.root, r.active, r.config))
+            .collect();
+        Ok((self.service_configs()?, roots))
+    }
+}
+
+impl Store for SqliteStore {
+    fn service_configs(&mut self) -> Result<Vec<(String, Value)>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT service, config FROM config ORDER BY service")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, parse_json(row.get(1)?)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn set_service_config(&mut self, service: &str, config: &Value) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO config (service, config) VALUES (?1, ?2) \
+                ON CONFLICT (service) DO UPDATE SET config = excluded.config",
+            params![service, json_text(config)],
+        )?;
+        Ok(())
+    }
+
+    /// SQLite can't notify other connections, so poll the configuration instead
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<bool> {
+        thread::sleep(timeout);
+        let current = self.snapshot()?;
+        let changed = self.seen.as_ref().is_some_and(|seen| *seen != current);
+        self.seen = Some(current);
+        Ok(changed)
+    }
+
+    fn video_extensions(&mut self) -> Result<Vec<String>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT extension FROM video_extensions ORDER BY extension")?;
+        let rows = statement.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn set_video_extensions(&mut self, extensions: &[String]) -> Result<()> {
+        let transaction = self.conn.transaction()?;
+        transaction.execute("DELETE FROM video_extensions", [])?;
+        for extension in extensions {
+            transaction.execute(
+                "INSERT OR IGNORE INTO video_extensions (extension) VALUES (?1)",
+                [extension],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn roots(&mut self) -> Result<Vec<RootRecord>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT root, active, config, last_scanned FROM roots ORDER BY root")?;
+        let rows = statement.query_map([], |row| {
+            Ok(RootRecord {
+                root: row.get(0)?,
+                active: row.get(1)?,
+                config: parse_json(row.get(2)?)?,
+                last_scanned: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn add_root(&mut self, root: &str, active: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO roots (root, active) VALUES (?1, ?2) \
+                ON CONFLICT (root) DO UPDATE SET active = excluded.active \
+                WHERE roots.active != excluded.active",
+            params![root, active],
+        )?;
+        Ok(())
+    }
+
+    fn remove_root(&mut self, root: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .execute("DELETE FROM roots WHERE root = ?1", [root])?
+            > 0)
+    }
+
+    fn set_root_config(&mut self, root: &str, config: &Value) -> Result<bool> {
+        Ok(self.conn.execute(
+            "UPDATE roots SET config = ?2 WHERE root = ?1",
+            params![root, json_text(config)],
+        )? > 0)
+    }
+
+    fn mark_scanned(&mut self, root: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE roots SET last_scanned = ?2 WHERE root = ?1",
+            params![root, now()],
+        )?;
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str) -> Result<Option<FileRecord>> {
+        let query = format!("SELECT {} FROM paths WHERE path = ?1", FILE_COLUMNS);
+        Ok(self
+            .conn
+            .query_row(&query, [path], file_from_row)
+            .optional()?)
+    }
+
+    fn store_file(&mut self, file: &FileRecord) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO paths (hash, path, codec, height, width, kbps, extension, bytes, \
+                last_modified, settled_at) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) \
+                ON CONFLICT (path) DO UPDATE SET hash = excluded.hash, codec = excluded.codec, \
+                    height = excluded.height, width = excluded.width, kbps = excluded.kbps, \
+                    extension = excluded.extension, bytes = excluded.bytes, \
+                    last_modified = excluded.last_modified, settled_at = excluded.settled_at, \
+                    missing_since = NULL, health = NULL, health_errors = NULL, \
+                    health_checked_at = NULL",
+            params![
+                file.hash,
+                file.path,
+                file.codec,
+                file.height,
+                file.width,
+                file.kbps,
+                file.extension,
+                file.bytes,
+                utc(&file.last_modified),
+                now(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn mark_settled(&mut self, path: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE paths SET settled_at = coalesce(settled_at, ?2), missing_since = NULL \
+                WHERE path = ?1 AND (settled_at IS NULL OR missing_since IS NOT NULL)",
+            params![path, now()],
+        )?;
+        Ok(())
+    }
+
+    fn mark_unsettled(&mut self, path: &str) -> Result<()> {
+        self.conn
+            .execute("UPDATE paths SET settled_at = NULL WHERE path = ?1", [path])?;
+        Ok(())
+    }
+
+    fn health(&mut self, path: &str) -> Result<Option<HealthRecord>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT path, health, health_errors, health_checked_at FROM paths \
+                    WHERE path = ?1 AND health IS NOT NULL",
+                [path],
+                health_from_row,
+            )
+            .optional()?)
+    }
+
+    fn set_health(&mut self, health: &HealthRecord) -> Result<()> {
+        self.conn.execute(
+            "UPDATE paths SET health = ?2, health_errors = ?3, health_checked_at = ?4 \
+                WHERE path = ?1",
+            params![
+                health.path,
+                health.status,
+                health.errors,
+                utc(&health.checked_at)
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn damaged(&mut self) -> Result<Vec<HealthRecord>> {
+        let mut statement = self.conn.prepare(
+            "SELECT path, health, health_errors, health_checked_at FROM paths \
+                WHERE health = 'damaged' ORDER BY path",
+        )?;
+        let rows = statement.query_map([], health_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn library(&mut self) -> Result<Vec<FileRecord>> {
+        let query = format!("SELECT {} FROM paths ORDER BY path", FILE_COLUMNS);
+        let mut statement = self.conn.prepare(&query)?;
+        let rows = statement.query_map([], file_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn path_states(&mut self, after: Option<&str>, limit: i64) -> Result<Vec<PathState>> {
+        let mut statement = self.conn.prepare(
+            "SELECT path, missing_since FROM paths WHERE ?1 IS NULL OR path > ?1 \
+                ORDER BY path LIMIT ?2",
+        )?;
+        let rows = statement.query_map(params![after, limit], |row| {
+            Ok(PathState {
+                path: row.get(0)?,
+                missing_since: row.get(1)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn mark_missing(&mut self, paths: &[String]) -> Result<u64> {
+        let paths = Value::from(paths.to_vec()).to_string();
+        Ok(self.conn.execute(
+            "UPDATE paths SET missing_since = ?2 \
+                WHERE path IN (SELECT value FROM json_each(?1)) AND missing_since IS NULL",
+            params![paths, now()],
+        )? as u64)
+    }
+
+    fn mark_found(&mut self, paths: &[String]) -> Result<u64> {
+        let paths = Value::from(paths.to_vec()).to_string();
+        Ok(self.conn.execute(
+            "UPDATE paths SET missing_since = NULL \
+                WHERE path IN (SELECT value FROM json_each(?1)) AND missing_since IS NOT NULL",
+            [paths],
+        )? as u64)
+    }
+
+    fn delete_paths(&mut self, paths: &[String]) -> Result<u64> {
+        let paths = Value::from(paths.to_vec()).to_string();
+        Ok(self.conn.execute(
+            "DELETE FROM paths WHERE path IN (SELECT value FROM json_each(?1))",
+            [paths],
+        )? as u64)
+    }
+
+    fn candidates(
+        &mut self,
+        targets: &[Target],
+        order: QueueOrder,
+        limit: i64,
+    ) -> Result<Vec<Candidate>> {
+        let query = format!("{} LIMIT ?2", candidates_query(order));
+        let mut statement = self.conn.prepare(&query)?;
+        let rows =
+            statement.query_map(params![targets_json(targets), limit], candidate_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn claim_next(&mut self, targets: &[Target], order: QueueOrder) -> Result<Option<Candidate>> {
+        let query = format!(
+            "UPDATE paths SET in_progress = true WHERE id = (SELECT id FROM ({} LIMIT 1)) \
+                AND NOT in_progress RETURNING id, path, bytes",
+            candidates_query(order)
+        );
+        Ok(self
+            .conn
+            .query_row(&query, [targets_json(targets)], candidate_from_row)
+            .optional()?)
+    }
+
+    fn claim_path(&mut self, path: &str) -> Result<Option<Candidate>> {
+        Ok(self
+            .conn
+            .query_row(
+                "UPDATE paths SET in_progress = true WHERE path = ?1 AND NOT in_progress \
+                    RETURNING id, path, bytes",
+                [path],
+                candidate_from_row,
+            )
+            .optional()?)
+    }
+
+    fn release(&mut self, id: i64) -> Result<()> {
+        self.conn
+            .execute("UPDATE paths SET in_progress = false WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn keep_original(&mut self, id: i64, encoded_path: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE paths SET in_progress = false, encoded_path = ?2 WHERE id = ?1",
+            params![id, encoded_path],
+        )?;
+        Ok(())
+    }
+
+    fn set_priority(&mut self, path: &str, priority: i32) -> Result<u64> {
+        Ok(self.conn.execute(
+            "UPDATE paths SET priority = ?2 \
+                WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+            params![path, priority],
+        )? as u64)
+    }
+
+    fn encoded_from(&mut self, path: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT encoded_from FROM paths WHERE path = ?1",
+                [path],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    fn set_encoded_from(&mut self, path: &str, source: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE paths SET encoded_from = ?2 WHERE path = ?1",
+            params![path, source],
+        )?;
+        Ok(())
+    }
+
+    fn insert_job(&mut self, job: &JobRecord) -> Result<i64> {
+        Ok(self.conn.query_row(
+            "INSERT INTO reencode_jobs (path_id, source_path, state, started_at, updated_at) \
+                VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
+            params![
+                job.path_id,
+                job.source_path,
+                job.state,
+                utc(&job.started_at),
+                utc(&job.updated_at),
+            ],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn update_job(&mut self, job: &JobRecord) -> Result<()> {
+        self.conn.execute(
+            "UPDATE reencode_jobs SET path_id = ?2, source_path = ?3, output_path = ?4, \
+                state = ?5, updated_at = ?6, finished_at = ?7, duration = ?8, percent = ?9, \
+                fps = ?10, speed = ?11, eta_seconds = ?12, profile = ?13, command = ?14, \
+                source_probe = ?15, output_probe = ?16, source_bytes = ?17, \
+                output_bytes = ?18, bytes_saved = ?19, exit_status = ?20, stderr = ?21, \
+                error = ?22, quality_metric = ?23, quality_score = ?24 \
+                WHERE id = ?1",
+            params![
+                job.id,
+                job.path_id,
+                job.source_path,
+                job.output_path,
+                job.state,
+                utc(&job.updated_at),
+                job.finished_at.as_ref().map(utc),
+                job.duration,
+                job.percent,
+                job.fps,
+                job.speed,
+                job.eta_seconds,
+                job.profile,
+                job.command,
+                job.source_probe.as_ref().map(json_text),
+                job.output_probe.as_ref().map(json_text),
+                job.source_bytes,
+                job.output_bytes,
+                job.bytes_saved,
+                job.exit_status,
+                job.stderr,
+                job.error,
+                job.quality_metric,
+                job.quality_score,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn retry_job(&mut self, id: i64, stale_before: DateTime<Local>) -> Result<bool> {
+        let transaction = self.conn.transaction()?;
+        let released = transaction.execute(
+            "UPDATE paths SET in_progress = false WHERE id = ( \
+                SELECT path_id FROM reencode_jobs WHERE id = ?1 \
+                    AND (state NOT IN ('running', 'paused') OR updated_at < ?2))",
+            params![id, utc(&stale_before)],
+        )?;
+        transaction.execute(
+            "UPDATE reencode_jobs SET state = 'failed', error = ?3, finished_at = ?4, \
+                eta_seconds = NULL \
+                WHERE id = ?1 AND state IN ('running', 'paused') AND updated_at < ?2",
+            params![id, utc(&stale_before), ABANDONED, now()],
+        )?;
+        transaction.commit()?;
+        Ok(released > 0)
+    }
+
+    fn claimed_jobs(&mut self) -> Result<Vec<JobRecord>> {
+        let query = format!(
+            "SELECT {} FROM reencode_jobs j WHERE j.state != 'succeeded' \
+                AND j.id = (SELECT max(id) FROM reencode_jobs l WHERE l.path_id = j.path_id) \
+                AND j.path_id IN (SELECT id FROM paths WHERE in_progress) \
+                ORDER BY j.id",
+            JOB_COLUMNS
+        );
+        let mut statement = self.conn.prepare(&query)?;
+        let rows = statement.query_map([], job_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn jobs_in_state(&mut self, state: &str) -> Result<Vec<JobRecord>> {
+        let query = format!(
+            "SELECT {} FROM reencode_jobs WHERE state = ?1 ORDER BY id",
+            JOB_COLUMNS
+        );
+        let mut statement = self.conn.prepare(&query)?;
+        let rows = statement.query_map([state], job_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+#[cfg(test)]
+impl crate::store::memory::TestStore for SqliteStore {
+    fn kind(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn connect(&self) -> Box<dyn crate::store::memory::TestStore> {
+        let path = self.conn.path().expect("in-memory database");
+        Box::new(SqliteStore::open(Path::new(path)).unwrap())
+    }
+
+    fn row(&mut self, path: &str) -> Option<crate::store::memory::PathRow> {
+        let query = format!(
+            "SELECT {}, id, in_progress, priority, encoded_path, encoded_from, settled_at, \
+                missing_since FROM paths WHERE path = ?1",
+            FILE_COLUMNS
+        );
+        let row = self
+            .conn
+            .query_row(&query, [path], |row| {
+                Ok(crate::store::memory::PathRow {
+                    id: row.get("id")?,
+                    file: file_from_row(row)?,
+                    in_progress: row.get("in_progress")?,
+                    priority: row.get("priority")?,
+                    encoded_path: row.get("encoded_path")?,
+                    encoded_from: row.get("encoded_from")?,
+                    settled_at: row.get("settled_at")?,
+                    missing_since: row.get("missing_since")?,
+                    health: None,
+                })
+            })
+            .optional()
+            .unwrap()?;
+        Some(crate::store::memory::PathRow {
+            health: self.health(path).unwrap(),
+            ..row
+        })
+    }
+}